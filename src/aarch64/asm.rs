@@ -106,12 +106,17 @@ pub unsafe fn write_kernel_page_table(root_paddr: PhysAddr) {
 ///
 /// Note that the TLB is **NOT** flushed after this operation.
 ///
+/// If KPTI is enabled, the ASID (bits 63:48) must be even, which is also set
+/// to `TTBR1_EL1` for kernel space, see the `kpti` module.
+///
 /// # Safety
 ///
 /// This function is unsafe as it changes the virtual memory address space.
 #[inline]
 pub unsafe fn write_user_page_table(root_paddr: PhysAddr) {
     TTBR0_EL1.set(root_paddr.as_usize() as _);
+    #[cfg(all(feature = "kpti", not(feature = "arm-el2")))]
+    super::kpti::set_asid(root_paddr.as_usize() as _);
 }
 
 /// Flushes the TLB.
@@ -121,8 +126,7 @@ pub unsafe fn write_user_page_table(root_paddr: PhysAddr) {
 #[inline]
 pub fn flush_tlb(vaddr: Option<VirtAddr>) {
     if let Some(vaddr) = vaddr {
-        const VA_MASK: usize = (1 << 44) - 1; // VA[55:12] => bits[43:0]
-        let operand = (vaddr.as_usize() >> 12) & VA_MASK;
+        let operand = (vaddr.as_usize() as u64 >> 12) & VA_MASK;
 
         #[cfg(not(feature = "arm-el2"))]
         unsafe {
@@ -149,18 +153,330 @@ pub fn flush_tlb(vaddr: Option<VirtAddr>) {
     }
 }
 
+/// Flushes the TLB entries that map the given virtual address range.
+///
+/// If `asid` is [`None`], flushes the entries of all address spaces. Otherwise,
+/// only flushes the non-global entries of the given ASID. If the range covers
+/// more pages than [`flush_range_threshold`], the whole address space is
+/// flushed instead.
+///
+/// In AArch64, the inner shareable `TLBI` operations are used, so the TLBs of
+/// all CPUs are flushed. The range operations (e.g., `TLBI RVAE1IS`) are used
+/// for the lower VA range if `FEAT_TLBIRANGE` is supported and the 4K granule
+/// is used without 52-bit addresses.
+///
+/// [`flush_range_threshold`]: crate::tlb::flush_range_threshold
+pub fn flush_tlb_range(start: VirtAddr, size: usize, asid: Option<usize>) {
+    const BADDR_MASK: u64 = (1 << 37) - 1; // VA[48:12] => bits[36:0]
+    const TG_4K: u64 = 0b01 << 46;
+
+    let pages = crate::tlb::page_range(start.as_usize(), size);
+    if pages.len() > crate::tlb::flush_range_threshold() {
+        match asid {
+            Some(asid) => flush_tlb_asid(asid),
+            None => flush_tlb_all_cpus(),
+        }
+        return;
+    }
+
+    let asid_bits = asid.map_or(0, |asid| (asid as u64 & 0xffff) << 48);
+    // BaseADDR of the range operations only holds VA[48:12] (with the 4K
+    // granule and `TCR_ELx.DS` == 0), which does not include VA[55] that
+    // selects between `TTBR0_ELx` and `TTBR1_ELx`. To avoid relying on how the
+    // upper bits are inferred, the upper VA range (e.g., kernel mappings) is
+    // flushed page by page, where the operand holds VA[55:12].
+    let upper = start.as_usize() as u64 & (1 << 55) != 0;
+    let range_supported = !upper && tlbi_range_supported();
+    let (mut page, end) = (pages.start as u64, pages.end as u64);
+    unsafe { asm!("dsb ishst") };
+    while page < end {
+        let num_pages = end - page;
+        if !range_supported || num_pages % 2 == 1 {
+            tlbi_va(asid_bits | (page & VA_MASK), asid.is_some());
+            page += 1;
+        } else {
+            // Each range operation flushes `(NUM + 1) << (5 * SCALE + 1)` pages.
+            let scale = (0..=3)
+                .rev()
+                .find(|s| num_pages >> (5 * s + 1) > 0)
+                .unwrap();
+            let num = (num_pages >> (5 * scale + 1)).min(32);
+            let operand = asid_bits | TG_4K | scale << 44 | (num - 1) << 39 | (page & BADDR_MASK);
+            tlbi_rva(operand, asid.is_some());
+            page += num << (5 * scale + 1);
+        }
+    }
+    unsafe { asm!("dsb ish; isb") };
+    // The user half of the ASID pair has its own TLB entries.
+    #[cfg(all(feature = "kpti", not(feature = "arm-el2")))]
+    match asid {
+        Some(asid) if asid & super::kpti::USER_ASID_BIT == 0 && super::kpti::is_enabled() => {
+            flush_tlb_range(start, size, Some(asid | super::kpti::USER_ASID_BIT))
+        }
+        _ => {}
+    }
+}
+
+/// Flushes all non-global TLB entries of the given ASID.
+///
+/// In AArch64, the inner shareable `TLBI` operations are used, so the TLBs of
+/// all CPUs are flushed. ASIDs are not used in EL2, so the entire TLB is
+/// flushed with the `arm-el2` feature.
+#[inline]
+pub fn flush_tlb_asid(asid: usize) {
+    #[cfg(not(feature = "arm-el2"))]
+    unsafe {
+        // TLB Invalidate by ASID, EL1, Inner Shareable
+        asm!("dsb ishst; tlbi aside1is, {}; dsb ish; isb", in(reg) (asid as u64 & 0xffff) << 48)
+    }
+    // The user half of the ASID pair has its own TLB entries.
+    #[cfg(all(feature = "kpti", not(feature = "arm-el2")))]
+    if asid & super::kpti::USER_ASID_BIT == 0 && super::kpti::is_enabled() {
+        flush_tlb_asid(asid | super::kpti::USER_ASID_BIT);
+    }
+    #[cfg(feature = "arm-el2")]
+    {
+        let _ = asid;
+        flush_tlb_all_cpus();
+    }
+}
+
+/// Flushes the entire TLB of all CPUs in the inner shareable domain.
+#[inline]
+pub(crate) fn flush_tlb_all_cpus() {
+    #[cfg(not(feature = "arm-el2"))]
+    unsafe {
+        // TLB Invalidate by VMID, All at stage 1, EL1, Inner Shareable
+        asm!("dsb ishst; tlbi vmalle1is; dsb ish; isb")
+    }
+    #[cfg(feature = "arm-el2")]
+    unsafe {
+        // TLB Invalidate All, EL2, Inner Shareable
+        asm!("dsb ishst; tlbi alle2is; dsb ish; isb")
+    }
+}
+
+/// VA[55:12] => bits[43:0] of the `TLBI` operand.
+const VA_MASK: u64 = (1 << 44) - 1;
+
+/// Returns whether the TLB range operations can be used for the lower VA
+/// range, i.e., `FEAT_TLBIRANGE` is supported, the 4K granule is used, and
+/// `TCR_ELx.DS` is clear. With `TCR_ELx.DS` set (52-bit addresses), BaseADDR
+/// holds VA[52:16] instead, which is not supported.
+fn tlbi_range_supported() -> bool {
+    // ID_AA64ISAR0_EL1.TLB == 0b0010
+    let tlbirange = (ID_AA64ISAR0_EL1.get() >> 56) & 0xf == 0b0010;
+    #[cfg(not(feature = "arm-el2"))]
+    let (granule_4k, ds) = (
+        TCR_EL1.read(TCR_EL1::TG0) == 0b00,
+        TCR_EL1.get() & (1 << 59) != 0,
+    );
+    // `TCR_EL2.DS` is bit 32 when `HCR_EL2.E2H` is clear.
+    #[cfg(feature = "arm-el2")]
+    let (granule_4k, ds) = (
+        TCR_EL2.read(TCR_EL2::TG0) == 0b00,
+        TCR_EL2.get() & (1 << 32) != 0,
+    );
+    tlbirange && granule_4k && !ds
+}
+
+/// TLB invalidate by VA, Inner Shareable.
+#[inline]
+#[cfg_attr(feature = "arm-el2", allow(unused_variables))]
+fn tlbi_va(operand: u64, by_asid: bool) {
+    #[cfg(not(feature = "arm-el2"))]
+    unsafe {
+        if by_asid {
+            asm!("tlbi vae1is, {}", in(reg) operand)
+        } else {
+            asm!("tlbi vaae1is, {}", in(reg) operand)
+        }
+    }
+    #[cfg(feature = "arm-el2")]
+    unsafe {
+        asm!("tlbi vae2is, {}", in(reg) operand)
+    }
+}
+
+/// TLB range invalidate by VA, Inner Shareable.
+///
+/// The `SYS` encodings are used, as they require no `FEAT_TLBIRANGE` support
+/// from the assembler.
+#[inline]
+#[cfg_attr(feature = "arm-el2", allow(unused_variables))]
+fn tlbi_rva(operand: u64, by_asid: bool) {
+    #[cfg(not(feature = "arm-el2"))]
+    unsafe {
+        if by_asid {
+            // TLBI RVAE1IS
+            asm!("sys #0, c8, c2, #1, {}", in(reg) operand)
+        } else {
+            // TLBI RVAAE1IS
+            asm!("sys #0, c8, c2, #3, {}", in(reg) operand)
+        }
+    }
+    #[cfg(feature = "arm-el2")]
+    unsafe {
+        // TLBI RVAE2IS
+        asm!("sys #4, c8, c2, #1, {}", in(reg) operand)
+    }
+}
+
 /// Flushes the entire instruction cache.
 #[inline]
 pub fn flush_icache_all() {
     unsafe { asm!("ic iallu; dsb sy; isb") };
 }
 
-/// Flushes the data cache line (64 bytes) at the given virtual address
+/// Invalidates the data cache line at the given virtual address to the point
+/// of coherency (`DC IVAC`), without writing it back.
+///
+/// See [`dcache_invalidate_range`] and [`dcache_clean_invalidate_range`] for
+/// range operations.
 #[inline]
 pub fn flush_dcache_line(vaddr: VirtAddr) {
     unsafe { asm!("dc ivac, {0:x}; dsb sy; isb", in(reg) vaddr.as_usize()) };
 }
 
+/// Reads the Cache Type Register (`CTR_EL0`).
+#[inline]
+fn read_ctr_el0() -> usize {
+    let ctr;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr) };
+    ctr
+}
+
+/// Returns the smallest data and instruction cache line sizes in bytes, read
+/// from `CTR_EL0`.
+#[inline]
+fn cache_line_sizes() -> (usize, usize) {
+    let ctr = read_ctr_el0();
+    let dline = 4 << ((ctr >> 16) & 0xf);
+    let iline = 4 << (ctr & 0xf);
+    (dline, iline)
+}
+
+/// Performs the data cache maintenance operation `op` on each line of the
+/// given range.
+macro_rules! dcache_range_op {
+    ($op:literal, $start:expr, $size:expr) => {{
+        let (line, _) = cache_line_sizes();
+        let start = $start.as_usize() & !(line - 1);
+        let end = $start.as_usize().saturating_add($size);
+        for addr in (start..end).step_by(line) {
+            asm!(concat!("dc ", $op, ", {}"), in(reg) addr);
+        }
+        asm!("dsb sy");
+    }};
+}
+
+/// Cleans the data cache lines covering the given range to the point of
+/// coherency (`DC CVAC`), i.e., writes the dirty lines back to memory.
+///
+/// It is used before a device reads the memory by DMA.
+#[inline]
+pub fn dcache_clean_range(start: VirtAddr, size: usize) {
+    unsafe { dcache_range_op!("cvac", start, size) }
+}
+
+/// Invalidates the data cache lines covering the given range to the point of
+/// coherency (`DC IVAC`), without writing them back.
+///
+/// It is used after a device writes the memory by DMA.
+///
+/// # Safety
+///
+/// This function is unsafe as the dirty data in the cache lines is discarded,
+/// including data outside the range but in the same cache lines.
+#[inline]
+pub unsafe fn dcache_invalidate_range(start: VirtAddr, size: usize) {
+    unsafe { dcache_range_op!("ivac", start, size) }
+}
+
+/// Cleans and invalidates the data cache lines covering the given range to the
+/// point of coherency (`DC CIVAC`).
+#[inline]
+pub fn dcache_clean_invalidate_range(start: VirtAddr, size: usize) {
+    unsafe { dcache_range_op!("civac", start, size) }
+}
+
+/// Makes the instruction fetches from the given range coherent with the
+/// previous data writes, e.g., after loading or modifying code.
+///
+/// It cleans the data cache to the point of unification (`DC CVAU`), then
+/// invalidates the instruction cache (`IC IVAU`) of all CPUs in the inner
+/// shareable domain. Either step is skipped if not required by `CTR_EL0`.
+pub fn icache_sync_range(start: VirtAddr, size: usize) {
+    let ctr = read_ctr_el0();
+    let (dline, iline) = cache_line_sizes();
+    let end = start.as_usize().saturating_add(size);
+    unsafe {
+        // CTR_EL0.IDC: data cache clean to the PoU is not required.
+        if ctr & (1 << 28) == 0 {
+            for addr in (start.as_usize() & !(dline - 1)..end).step_by(dline) {
+                asm!("dc cvau, {}", in(reg) addr);
+            }
+        }
+        asm!("dsb ish");
+        // CTR_EL0.DIC: instruction cache invalidation to the PoU is not required.
+        if ctr & (1 << 29) == 0 {
+            for addr in (start.as_usize() & !(iline - 1)..end).step_by(iline) {
+                asm!("ic ivau, {}", in(reg) addr);
+            }
+            asm!("dsb ish");
+        }
+        asm!("isb");
+    }
+}
+
+/// Performs the data cache maintenance operation `op` by set/way on all levels
+/// of data caches up to the point of coherency.
+macro_rules! dcache_all_op {
+    ($op:literal) => {{
+        let clidr = CLIDR_EL1.get();
+        let loc = (clidr >> 24) & 0x7;
+        for level in 0..loc {
+            // Skip the levels without data caches (Ctype < 2).
+            if (clidr >> (level * 3)) & 0x7 < 2 {
+                continue;
+            }
+            CSSELR_EL1.set(level << 1);
+            barrier::isb(barrier::SY);
+            let ccsidr = CCSIDR_EL1.get();
+            let line_shift = (ccsidr & 0x7) + 4;
+            let ways = ((ccsidr >> 3) & 0x3ff) + 1;
+            let sets = ((ccsidr >> 13) & 0x7fff) + 1;
+            let way_shift = (ways as u32 - 1).leading_zeros();
+            for way in 0..ways {
+                for set in 0..sets {
+                    let operand = (way << way_shift) | (set << line_shift) | (level << 1);
+                    asm!(concat!("dc ", $op, ", {}"), in(reg) operand);
+                }
+            }
+        }
+        asm!("dsb sy; isb");
+    }};
+}
+
+/// Cleans and invalidates all data caches of the current CPU by set/way
+/// (`DC CISW`), e.g., before powering it down.
+///
+/// It should be called with the data cache disabled (`SCTLR_EL1.C`), otherwise
+/// the lines may be allocated again.
+pub fn dcache_clean_invalidate_all() {
+    unsafe { dcache_all_op!("cisw") }
+}
+
+/// Invalidates all data caches of the current CPU by set/way (`DC ISW`),
+/// without writing them back, e.g., before enabling the data cache at boot.
+///
+/// # Safety
+///
+/// This function is unsafe as all dirty data in the caches is discarded.
+pub unsafe fn dcache_invalidate_all() {
+    unsafe { dcache_all_op!("isw") }
+}
+
 /// Writes exception vector base address register (`VBAR_EL1`).
 ///
 /// # Safety
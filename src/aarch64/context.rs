@@ -87,6 +87,69 @@ impl FpState {
     }
 }
 
+/// FP control and status registers of a task.
+///
+/// It is a lightweight alternative of [`FpState`], which only preserves `FPCR`
+/// and `FPSR` across context switches (the `fp-ctrl` feature without
+/// `fp-simd`), so that the rounding modes of a task do not leak into others.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FpControlState {
+    /// Floating-point Control Register (FPCR)
+    pub fpcr: u32,
+    /// Floating-point Status Register (FPSR)
+    pub fpsr: u32,
+}
+
+#[cfg(feature = "fp-ctrl")]
+impl FpControlState {
+    /// Saves the current FP control registers from CPU to this structure.
+    ///
+    /// Nothing is saved if FP instructions are trapped at EL1.
+    pub fn save(&mut self) {
+        if fp_enabled() {
+            let (fpcr, fpsr): (u64, u64);
+            unsafe {
+                core::arch::asm!(
+                    ".arch armv8",
+                    "mrs {0}, fpcr",
+                    "mrs {1}, fpsr",
+                    out(reg) fpcr,
+                    out(reg) fpsr,
+                    options(nomem, nostack, preserves_flags),
+                )
+            };
+            self.fpcr = fpcr as _;
+            self.fpsr = fpsr as _;
+        }
+    }
+
+    /// Restores the FP control registers from this structure to CPU.
+    ///
+    /// Nothing is restored if FP instructions are trapped at EL1.
+    pub fn restore(&self) {
+        if fp_enabled() {
+            unsafe {
+                core::arch::asm!(
+                    ".arch armv8",
+                    "msr fpcr, {0}",
+                    "msr fpsr, {1}",
+                    in(reg) self.fpcr as u64,
+                    in(reg) self.fpsr as u64,
+                    options(nomem, nostack, preserves_flags),
+                )
+            }
+        }
+    }
+}
+
+/// Whether FP/SIMD instructions can be executed at EL1.
+#[cfg(feature = "fp-ctrl")]
+fn fp_enabled() -> bool {
+    use aarch64_cpu::registers::{Readable, CPACR_EL1};
+    CPACR_EL1.matches_all(CPACR_EL1::FPEN::TrapNothing)
+}
+
 /// Saved hardware states of a task.
 ///
 /// The context usually includes:
@@ -121,6 +184,9 @@ pub struct TaskContext {
     pub ttbr0_el1: memory_addr::PhysAddr,
     #[cfg(feature = "fp-simd")]
     pub fp_state: FpState,
+    /// FP control registers, when the full FP/SIMD states are not switched.
+    #[cfg(all(feature = "fp-ctrl", not(feature = "fp-simd")))]
+    pub fp_ctrl: FpControlState,
 }
 
 impl TaskContext {
@@ -137,6 +203,10 @@ impl TaskContext {
 
     /// Initializes the context for a new task, with the given entry point and
     /// kernel stack.
+    ///
+    /// The entry function must never return, see [`init_with_arg`] otherwise.
+    ///
+    /// [`init_with_arg`]: TaskContext::init_with_arg
     pub fn init(&mut self, entry: usize, kstack_top: VirtAddr, tls_area: VirtAddr) {
         self.sp = kstack_top.as_usize() as u64;
         self.lr = entry as u64;
@@ -144,6 +214,27 @@ impl TaskContext {
         self.tpidr_el0 = tls_area.as_usize() as u64;
     }
 
+    /// Initializes the context for a new task, which calls `entry(arg)` on the
+    /// given kernel stack.
+    ///
+    /// If the task is first entered by
+    /// [`switch_to_irqsave`](Self::switch_to_irqsave), the hook registered to
+    /// [`FINISH_SWITCH`](crate::task::FINISH_SWITCH) is called and IRQs are
+    /// enabled before the entry function. If the entry function returns, the
+    /// hook registered to
+    /// [`TASK_EXIT`](crate::task::TASK_EXIT) is called on the same stack.
+    pub fn init_with_arg(
+        &mut self,
+        entry: usize,
+        arg: usize,
+        kstack_top: VirtAddr,
+        tls_area: VirtAddr,
+    ) {
+        self.init(task_entry as *const () as _, kstack_top, tls_area);
+        self.r19 = entry as _;
+        self.r20 = arg as _;
+    }
+
     /// Changes the page table root in this context.
     ///
     /// The hardware register for user page table root (`ttbr0_el1` for aarch64 in EL1)
@@ -157,23 +248,221 @@ impl TaskContext {
     ///
     /// It first saves the current task's context from CPU to this place, and then
     /// restores the next task's context from `next_ctx` to CPU.
+    ///
+    /// It does not change the IRQ state. However, if the current task is
+    /// switched back by [`switch_to_irqsave`](Self::switch_to_irqsave), the
+    /// hook registered to [`FINISH_SWITCH`](crate::task::FINISH_SWITCH) is
+    /// called, and IRQs are restored to the state before this function was
+    /// called.
     pub fn switch_to(&mut self, next_ctx: &Self) {
+        let irqs_were_enabled = crate::asm::irqs_enabled();
+        if self.switch_to_impl(next_ctx, false) {
+            crate::task::finish_switch(irqs_were_enabled);
+        }
+    }
+
+    /// Switches to another task, where `irqsave` is passed to the next task,
+    /// i.e., to the entry trampoline if it is newly created, or as the return
+    /// value of this function in the next task otherwise.
+    ///
+    /// Returns the `irqsave` argument of the switch that resumes the current
+    /// task (`false` if resumed by [`Self::load`]).
+    pub(crate) fn switch_to_impl(&mut self, next_ctx: &Self, irqsave: bool) -> bool {
         #[cfg(feature = "fp-simd")]
         {
             self.fp_state.save();
             next_ctx.fp_state.restore();
         }
+        #[cfg(all(feature = "fp-ctrl", not(feature = "fp-simd")))]
+        {
+            self.fp_ctrl.save();
+            next_ctx.fp_ctrl.restore();
+        }
+        #[cfg(feature = "uspace")]
+        {
+            #[cfg(feature = "tlb-shootdown")]
+            crate::tlb::set_active_root(next_ctx.ttbr0_el1);
+            if self.ttbr0_el1 != next_ctx.ttbr0_el1 {
+                unsafe { crate::asm::write_user_page_table(next_ctx.ttbr0_el1) };
+                crate::asm::flush_tlb(None); // currently flush the entire TLB
+            }
+        }
+        unsafe { context_switch(self, next_ctx, irqsave) }
+    }
+
+    /// Restores this context to CPU and jumps to it, without saving the
+    /// current context.
+    ///
+    /// It can be used to enter the first task on a CPU.
+    ///
+    /// # Safety
+    ///
+    /// The context must be initialized by [`init`] or saved by [`switch_to`].
+    /// The current stack is abandoned.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`switch_to`]: TaskContext::switch_to
+    pub unsafe fn load(&self) -> ! {
+        unsafe { self.load_from(None) }
+    }
+
+    /// Switches to another task without saving the current task's context.
+    ///
+    /// It can be used when the current task is exiting, as the current stack
+    /// is never touched after switching, so it can be freed by the next task.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`load`](TaskContext::load).
+    pub unsafe fn switch_to_no_save(&self, next_ctx: &Self) -> ! {
+        unsafe { next_ctx.load_from(Some(self)) }
+    }
+
+    #[cfg_attr(not(feature = "uspace"), allow(unused_variables))]
+    unsafe fn load_from(&self, prev: Option<&Self>) -> ! {
+        #[cfg(feature = "fp-simd")]
+        self.fp_state.restore();
+        #[cfg(all(feature = "fp-ctrl", not(feature = "fp-simd")))]
+        self.fp_ctrl.restore();
         #[cfg(feature = "uspace")]
-        if self.ttbr0_el1 != next_ctx.ttbr0_el1 {
-            unsafe { crate::asm::write_user_page_table(next_ctx.ttbr0_el1) };
-            crate::asm::flush_tlb(None); // currently flush the entire TLB
+        {
+            let cur_root = prev.map_or_else(crate::asm::read_user_page_table, |p| p.ttbr0_el1);
+            #[cfg(feature = "tlb-shootdown")]
+            crate::tlb::set_active_root(self.ttbr0_el1);
+            if self.ttbr0_el1 != cur_root {
+                unsafe { crate::asm::write_user_page_table(self.ttbr0_el1) };
+                crate::asm::flush_tlb(None);
+            }
+        }
+        unsafe { context_load(self) }
+    }
+}
+
+/// A minimal context for stackful coroutines (fibers).
+///
+/// Unlike [`TaskContext`], it only holds the stack pointer, the thread pointer
+/// and the callee-saved registers. FP/SIMD states and the page table root are
+/// not touched.
+///
+/// When the entry function of a fiber returns, the fiber switches back to the
+/// context that most recently switched to it.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct FiberContext {
+    // The same layout as the beginning of `TaskContext`, so that they can share
+    // the `context_switch` routine.
+    sp: u64,
+    tpidr_el0: u64,
+    r: [u64; 11], // r19..r29
+    lr: u64,
+    /// Slot on the fiber stack that records the context to resume when the
+    /// fiber finishes.
+    resume_slot: usize,
+}
+
+static_assertions::const_assert_eq!(
+    core::mem::offset_of!(FiberContext, lr),
+    core::mem::offset_of!(TaskContext, lr)
+);
+
+impl FiberContext {
+    /// Creates an empty context.
+    ///
+    /// It can be used as the context of the caller that starts fibers, which
+    /// will be filled by [`switch`](FiberContext::switch).
+    pub const fn new() -> Self {
+        Self {
+            sp: 0,
+            tpidr_el0: 0,
+            r: [0; 11],
+            lr: 0,
+            resume_slot: 0,
+        }
+    }
+
+    /// Initializes the context to run `entry(arg)` on the given stack.
+    ///
+    /// The fiber inherits the current thread pointer (`TPIDR_EL0`).
+    ///
+    /// # Safety
+    ///
+    /// `stack_top` must be the top of a stack that is valid for writes and
+    /// large enough for `entry`. The stack must not be used for other purposes
+    /// until the fiber finishes.
+    pub unsafe fn make_context(
+        &mut self,
+        stack_top: VirtAddr,
+        entry: extern "C" fn(usize),
+        arg: usize,
+    ) {
+        let resume_slot = stack_top.as_usize() - 16;
+        unsafe { (resume_slot as *mut usize).write(0) };
+        *self = Self {
+            sp: resume_slot as _,
+            tpidr_el0: crate::asm::read_thread_pointer() as _,
+            lr: entry_trampoline as *const () as _,
+            resume_slot,
+            ..Self::new()
+        };
+        self.r[0] = entry as usize as _;
+        self.r[1] = arg as _;
+        self.r[2] = fiber_exit as *const () as _;
+        self.r[3] = resume_slot as _;
+    }
+
+    /// Switches to another fiber.
+    ///
+    /// It saves the callee-saved registers to this place, and then restores
+    /// the ones of `next_ctx`.
+    pub fn switch(&mut self, next_ctx: &Self) {
+        if next_ctx.resume_slot != 0 {
+            unsafe { (next_ctx.resume_slot as *mut usize).write(self as *mut _ as usize) };
         }
-        unsafe { context_switch(self, next_ctx) }
+        unsafe { context_switch(self as *mut _ as _, next_ctx as *const _ as _, false) };
     }
 }
 
+/// Resumes the context recorded in `resume_slot` after a fiber finishes.
+unsafe extern "C" fn fiber_exit(resume_slot: *const *const FiberContext) -> ! {
+    unsafe { context_load(*resume_slot as _) }
+}
+
+/// Calls `x19(x20)`, then `x21(x22)` which must not return.
+#[unsafe(naked)]
+unsafe extern "C" fn entry_trampoline() -> ! {
+    naked_asm!(
+        "
+        mov     x0, x20
+        blr     x19
+        mov     x0, x22
+        blr     x21
+        udf     #0",
+    )
+}
+
+/// Finishes the context switch with the `irqsave` argument of `context_switch`
+/// left in `x2` (cleared by `context_load`), then calls `x19(x20)` and exits the task.
+#[unsafe(naked)]
+unsafe extern "C" fn task_entry() -> ! {
+    naked_asm!(
+        "
+        mov     x0, x2
+        bl      {finish_switch}
+        mov     x0, x20
+        blr     x19
+        bl      {task_exit}
+        udf     #0",
+        finish_switch = sym crate::task::task_entry_finish_switch,
+        task_exit = sym crate::task::task_exit,
+    )
+}
+
 #[unsafe(naked)]
-unsafe extern "C" fn context_switch(_current_task: &mut TaskContext, _next_task: &TaskContext) {
+unsafe extern "C" fn context_switch(
+    _current_task: *mut TaskContext,
+    _next_task: *const TaskContext,
+    _irqsave: bool,
+) -> bool {
     naked_asm!(
         "
         // save old context (callee-saved registers)
@@ -198,6 +487,27 @@ unsafe extern "C" fn context_switch(_current_task: &mut TaskContext, _next_task:
         ldp     x27, x28, [x1, 10 * 8]
         ldp     x29, x30, [x1, 12 * 8]
 
+        mov     x0, x2
+        ret",
+    )
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn context_load(_next_task: *const TaskContext) -> ! {
+    naked_asm!(
+        "
+        mov     x2, xzr
+        ldp     x19, x20, [x0]
+        mov     sp, x19
+        msr     tpidr_el0, x20
+        ldp     x19, x20, [x0, 2 * 8]
+        ldp     x21, x22, [x0, 4 * 8]
+        ldp     x23, x24, [x0, 6 * 8]
+        ldp     x25, x26, [x0, 8 * 8]
+        ldp     x27, x28, [x0, 10 * 8]
+        ldp     x29, x30, [x0, 12 * 8]
+
+        mov     x0, xzr
         ret",
     )
 }
@@ -3,6 +3,9 @@
 use aarch64_cpu::{asm::barrier, registers::*};
 use memory_addr::PhysAddr;
 
+#[cfg(feature = "uspace")]
+pub use super::uaccess::init_uaccess_protection;
+
 /// Swtich current exception level to EL1.
 ///
 /// It usually used in the system booting process, where the startup code is
@@ -51,40 +54,167 @@ pub unsafe fn switch_to_el1() {
     }
 }
 
+/// Translation granule sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granule {
+    /// 4 KiB granule.
+    Size4K,
+    /// 16 KiB granule.
+    Size16K,
+    /// 64 KiB granule.
+    Size64K,
+}
+
+/// Configuration of the MMU, used by [`init_mmu_with_config`].
+#[derive(Debug, Clone, Copy)]
+pub struct MmuConfig {
+    /// The translation granule for both `TTBR0_EL1` and `TTBR1_EL1`.
+    pub granule: Granule,
+    /// The virtual address size in bits for both `TTBR0_EL1` and `TTBR1_EL1`,
+    /// which can be 39, 42, 48 or 52.
+    ///
+    /// 52-bit VA with 4K or 16K granules requires `FEAT_LPA2`, and the page
+    /// tables must use the LPA2 descriptor format.
+    pub va_bits: u8,
+    /// The page table root for user space (`TTBR0_EL1`).
+    pub ttbr0_root: PhysAddr,
+    /// The page table root for kernel space (`TTBR1_EL1`).
+    pub ttbr1_root: PhysAddr,
+    /// Whether to disable the translation table walks using `TTBR0_EL1`
+    /// (`TCR_EL1.EPD0`), so that any access to user space faults.
+    pub disable_ttbr0_walks: bool,
+}
+
+impl MmuConfig {
+    /// Creates the conventional configuration, with a 4K granule, 48-bit
+    /// virtual addresses, and the same page table root for both `TTBR0_EL1`
+    /// and `TTBR1_EL1`.
+    pub const fn new(root_paddr: PhysAddr) -> Self {
+        Self {
+            granule: Granule::Size4K,
+            va_bits: 48,
+            ttbr0_root: root_paddr,
+            ttbr1_root: root_paddr,
+            disable_ttbr0_walks: false,
+        }
+    }
+}
+
+/// Errors returned by [`init_mmu_with_config`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmuConfigError {
+    /// The translation granule is not supported by the current CPU.
+    GranuleNotSupported,
+    /// The virtual address size is invalid or not supported by the current CPU.
+    VaBitsNotSupported,
+}
+
 /// Configures and enables the MMU on the current CPU.
 ///
-/// It first sets `MAIR_EL1`, `TCR_EL1`, `TTBR0_EL1`, `TTBR1_EL1` registers to
-/// the conventional values, and then enables the MMU and caches by setting
-/// `SCTLR_EL1`.
+/// It first sets `MAIR_EL1` (see [`MemoryType::MAIR_VALUE`]), `TCR_EL1`,
+/// `TTBR0_EL1`, `TTBR1_EL1` registers to the conventional values, and then
+/// enables the MMU and caches by setting `SCTLR_EL1`.
+///
+/// See [`init_mmu_with_config`] for other translation configurations.
+///
+/// [`MemoryType::MAIR_VALUE`]: crate::mem_type::MemoryType::MAIR_VALUE
 ///
 /// # Safety
 ///
 /// This function is unsafe as it changes the address translation configuration.
 pub unsafe fn init_mmu(root_paddr: PhysAddr) {
-    use page_table_entry::aarch64::MemAttr;
+    unsafe { init_mmu_with_config(&MmuConfig::new(root_paddr)) }
+        .expect("4K granule with 48-bit VA must be supported");
+}
 
-    MAIR_EL1.set(MemAttr::MAIR_VALUE);
+/// Configures and enables the MMU on the current CPU with the given
+/// configuration.
+///
+/// The physical address size (`TCR_EL1.IPS`) is derived from
+/// `ID_AA64MMFR0_EL1.PARange`. It is limited to 48 bits unless 52-bit output
+/// addresses are available with the chosen granule, i.e., 64K granule with
+/// `FEAT_LPA`, or 52-bit VA with `FEAT_LPA2`.
+///
+/// Returns an error without touching any register if the configuration is not
+/// supported by the current CPU.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the address translation configuration.
+pub unsafe fn init_mmu_with_config(config: &MmuConfig) -> Result<(), MmuConfigError> {
+    use crate::mem_type::MemoryType;
 
-    // Enable TTBR0 and TTBR1 walks, page size = 4K, vaddr size = 48 bits, paddr size = 48 bits.
-    let tcr_flags0 = TCR_EL1::EPD0::EnableTTBR0Walks
-        + TCR_EL1::TG0::KiB_4
+    let mmfr0 = ID_AA64MMFR0_EL1.extract();
+    // `true` if the granule supports 52-bit addresses with `FEAT_LPA2`.
+    let lpa2 = match config.granule {
+        Granule::Size4K => match mmfr0.read(ID_AA64MMFR0_EL1::TGran4) {
+            0b0000 => false,
+            0b0001 => true,
+            _ => return Err(MmuConfigError::GranuleNotSupported),
+        },
+        Granule::Size16K => match mmfr0.read(ID_AA64MMFR0_EL1::TGran16) {
+            0b0001 => false,
+            0b0010 => true,
+            _ => return Err(MmuConfigError::GranuleNotSupported),
+        },
+        Granule::Size64K => match mmfr0.read(ID_AA64MMFR0_EL1::TGran64) {
+            0b0000 => false,
+            _ => return Err(MmuConfigError::GranuleNotSupported),
+        },
+    };
+    let va52 = match config.va_bits {
+        39 | 42 | 48 => false,
+        52 if config.granule == Granule::Size64K => {
+            // FEAT_LVA
+            if ID_AA64MMFR2_EL1.read(ID_AA64MMFR2_EL1::VARange) != 1 {
+                return Err(MmuConfigError::VaBitsNotSupported);
+            }
+            true
+        }
+        52 if lpa2 => true,
+        _ => return Err(MmuConfigError::VaBitsNotSupported),
+    };
+    // TCR_EL1.DS: enables 52-bit addresses for 4K and 16K granules.
+    let ds = va52 && config.granule != Granule::Size64K;
+    let pa52_allowed = ds || config.granule == Granule::Size64K;
+    let ips = match mmfr0.read(ID_AA64MMFR0_EL1::PARange) {
+        pa @ 0..=0b0101 => pa,
+        0b0110 if pa52_allowed => 0b0110,
+        _ => 0b0101,
+    };
+
+    MAIR_EL1.set(MemoryType::MAIR_VALUE);
+
+    let tsz = 64 - config.va_bits as u64;
+    let (tg0, tg1) = match config.granule {
+        Granule::Size4K => (TCR_EL1::TG0::KiB_4, TCR_EL1::TG1::KiB_4),
+        Granule::Size16K => (TCR_EL1::TG0::KiB_16, TCR_EL1::TG1::KiB_16),
+        Granule::Size64K => (TCR_EL1::TG0::KiB_64, TCR_EL1::TG1::KiB_64),
+    };
+    let epd0 = if config.disable_ttbr0_walks {
+        TCR_EL1::EPD0::DisableTTBR0Walks
+    } else {
+        TCR_EL1::EPD0::EnableTTBR0Walks
+    };
+    let tcr_flags0 = epd0
+        + tg0
         + TCR_EL1::SH0::Inner
         + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
         + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-        + TCR_EL1::T0SZ.val(16);
+        + TCR_EL1::T0SZ.val(tsz);
     let tcr_flags1 = TCR_EL1::EPD1::EnableTTBR1Walks
-        + TCR_EL1::TG1::KiB_4
+        + tg1
         + TCR_EL1::SH1::Inner
         + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
         + TCR_EL1::IRGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-        + TCR_EL1::T1SZ.val(16);
-    TCR_EL1.write(TCR_EL1::IPS::Bits_48 + tcr_flags0 + tcr_flags1);
+        + TCR_EL1::T1SZ.val(tsz);
+    let tcr = (TCR_EL1::IPS.val(ips) + tcr_flags0 + tcr_flags1).value;
+    TCR_EL1.set(if ds { tcr | TCR_EL1_DS } else { tcr });
     barrier::isb(barrier::SY);
 
-    // Set both TTBR0 and TTBR1
-    let root_paddr = root_paddr.as_usize() as u64;
-    TTBR0_EL1.set(root_paddr);
-    TTBR1_EL1.set(root_paddr);
+    // Set TTBR0 and TTBR1
+    TTBR0_EL1.set(ttbr_baddr(config.ttbr0_root, pa52_allowed));
+    TTBR1_EL1.set(ttbr_baddr(config.ttbr1_root, pa52_allowed));
 
     // Flush the entire TLB
     crate::asm::flush_tlb(None);
@@ -92,18 +222,61 @@ pub unsafe fn init_mmu(root_paddr: PhysAddr) {
     // Enable the MMU and turn on I-cache and D-cache
     SCTLR_EL1.modify(SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable);
     barrier::isb(barrier::SY);
+    Ok(())
 }
 
+/// `TCR_EL1.DS`, which is not defined in `aarch64_cpu`.
+const TCR_EL1_DS: u64 = 1 << 59;
+
+/// Encodes the page table root into `TTBRx_EL1.BADDR`.
+///
+/// With 52-bit output addresses, bits [51:48] of the root are held in bits
+/// [5:2] of the register.
+const fn ttbr_baddr(root_paddr: PhysAddr, pa52: bool) -> u64 {
+    let root = root_paddr.as_usize() as u64;
+    if pa52 {
+        (root & 0xffff_ffff_ffc0) | ((root >> 48) & 0xf) << 2
+    } else {
+        root
+    }
+}
+
+static_assertions::const_assert_eq!(
+    ttbr_baddr(PhysAddr::from_usize(0x4008_1000), false),
+    0x4008_1000
+);
+static_assertions::const_assert_eq!(
+    ttbr_baddr(PhysAddr::from_usize(0x8000_4008_1000), true),
+    0x8000_4008_1000
+);
+static_assertions::const_assert_eq!(
+    ttbr_baddr(PhysAddr::from_usize(0x000f_0000_1234_0000), true),
+    0x1234_003c
+);
+static_assertions::const_assert_eq!(
+    ttbr_baddr(PhysAddr::from_usize(0x0005_8000_0000_0040), true),
+    0x8000_0000_0054
+);
+
 /// Initializes trap handling on the current CPU.
 ///
 /// In detail, it initializes the exception vector, and sets `TTBR0_EL1` to 0 to
 /// block low address access.
+///
+/// If the `tlb-shootdown` feature is enabled, it also marks the current CPU as
+/// online ([`mark_current_cpu_online`]), so that it receives TLB shootdowns.
+/// The [`CURRENT_CPU_ID`] hook must be registered in that case.
+///
+/// [`mark_current_cpu_online`]: crate::tlb::mark_current_cpu_online
+/// [`CURRENT_CPU_ID`]: crate::tlb::CURRENT_CPU_ID
 pub fn init_trap() {
     unsafe extern "C" {
         fn exception_vector_base();
     }
     unsafe {
-        crate::asm::write_exception_vector_base(exception_vector_base as usize);
+        crate::asm::write_exception_vector_base(exception_vector_base as *const () as usize);
         crate::asm::write_user_page_table(0.into());
     }
+    #[cfg(feature = "tlb-shootdown")]
+    crate::tlb::mark_current_cpu_online();
 }
@@ -0,0 +1,91 @@
+//! Kernel page-table isolation (KPTI).
+//!
+//! When the `kpti` feature is enabled and [`init_kpti`] is called, user space
+//! runs with a *trampoline page table* in `TTBR1_EL1`, which maps only the
+//! trampoline page (see [`trampoline_range`]) in the kernel space. The
+//! exception vector base (`VBAR_EL1`) is set to the trampoline vectors, which
+//! switch `TTBR1_EL1` to the kernel page table on exceptions from user space,
+//! and switch it back before returning to user space.
+//!
+//! The root of the trampoline page table must be located at
+//! [`TRAMPOLINE_ROOT_OFFSET`] after the root of the kernel page table, so that
+//! the trampoline can switch between them without accessing memory.
+//!
+//! To avoid flushing the TLB on each switch, the ASID is taken from
+//! `TTBR1_EL1` (`TCR_EL1.A1` is set). The kernel uses an even ASID, and user
+//! space uses the same ASID with [`USER_ASID_BIT`] set. Hence, the ASIDs set by
+//! [`write_user_page_table`] must be even. The kernel mappings should be
+//! non-global, otherwise they are still visible in user space via the TLB.
+//!
+//! The trampoline uses `TPIDRRO_EL0` as a scratch register, which is cleared
+//! on returning to user space.
+//!
+//! KPTI is not supported if the `arm-el2` feature is enabled.
+//!
+//! [`write_user_page_table`]: crate::asm::write_user_page_table
+
+use core::ops::Range;
+
+use aarch64_cpu::{asm::barrier, registers::*};
+use memory_addr::VirtAddr;
+
+/// The offset of the trampoline page table root from the kernel page table
+/// root.
+pub const TRAMPOLINE_ROOT_OFFSET: usize = 0x1000;
+
+/// The bit set in the ASID of user space.
+pub const USER_ASID_BIT: usize = 1;
+
+const ASID_SHIFT: u64 = 48;
+const ASID_MASK: u64 = 0xffff << ASID_SHIFT;
+
+/// Enables KPTI on the current CPU.
+///
+/// It sets `TCR_EL1.A1`, moves the ASID from `TTBR0_EL1` to `TTBR1_EL1`, and
+/// sets the exception vector base to the trampoline vectors. It should be
+/// called after [`init_trap`](crate::init::init_trap).
+///
+/// # Safety
+///
+/// The trampoline page table must have been set up at
+/// [`TRAMPOLINE_ROOT_OFFSET`] after the kernel page table root, mapping the
+/// [`trampoline_range`] at the same virtual address.
+pub unsafe fn init_kpti() {
+    unsafe extern "C" {
+        fn kpti_trampoline_vectors();
+    }
+    let asid = (TTBR0_EL1.get() >> ASID_SHIFT) & !(USER_ASID_BIT as u64);
+    TTBR1_EL1.set((TTBR1_EL1.get() & !ASID_MASK) | asid << ASID_SHIFT);
+    TCR_EL1.modify(TCR_EL1::A1::TTBR1);
+    barrier::isb(barrier::SY);
+    unsafe {
+        crate::asm::write_exception_vector_base(kpti_trampoline_vectors as *const () as usize);
+    }
+    crate::asm::flush_tlb(None);
+}
+
+/// Returns whether KPTI is enabled on the current CPU.
+#[inline]
+pub fn is_enabled() -> bool {
+    TCR_EL1.matches_all(TCR_EL1::A1::TTBR1)
+}
+
+/// Returns the address range of the trampoline page, which must be mapped in
+/// the trampoline page table.
+pub fn trampoline_range() -> Range<VirtAddr> {
+    unsafe extern "C" {
+        fn kpti_trampoline_start();
+        fn kpti_trampoline_end();
+    }
+    va!(kpti_trampoline_start as *const () as usize)..va!(kpti_trampoline_end as *const () as usize)
+}
+
+/// Sets the kernel ASID in `TTBR1_EL1` to the one in the given `TTBR0_EL1`
+/// value, if KPTI is enabled.
+#[inline]
+pub(super) fn set_asid(ttbr0: u64) {
+    if is_enabled() {
+        let asid = (ttbr0 >> ASID_SHIFT) & !(USER_ASID_BIT as u64);
+        TTBR1_EL1.set((TTBR1_EL1.get() & !ASID_MASK) | asid << ASID_SHIFT);
+    }
+}
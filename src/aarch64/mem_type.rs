@@ -0,0 +1,53 @@
+use page_table_entry::aarch64::MemAttr;
+
+use crate::mem_type::MemoryType;
+
+impl MemoryType {
+    /// The `MAIR_EL1` register should be set to this value to match the
+    /// attribute bits returned by [`MemoryType::pte_bits`].
+    ///
+    /// The indices 0 to 2 are the same as [`MemAttr::MAIR_VALUE`], i.e.,
+    /// Device-nGnRE, Normal write-back and Normal non-cacheable. The index 3 is
+    /// Normal write-through, and the index 4 is Device-nGnRnE.
+    pub const MAIR_VALUE: u64 = {
+        const NORMAL_WT: u64 = 0xbb;
+        const DEVICE_NGNRNE: u64 = 0x00;
+        MemAttr::MAIR_VALUE | (NORMAL_WT << 24) | (DEVICE_NGNRNE << 32)
+    };
+
+    /// Returns the attribute bits (`AttrIndx` and `SH`) of page table
+    /// descriptors.
+    pub const fn pte_bits(self) -> u64 {
+        const INNER_SHAREABLE: u64 = 0b11 << 8;
+        let index = self.mair_index();
+        match self {
+            Self::Device | Self::StronglyOrdered => index << 2,
+            _ => index << 2 | INNER_SHAREABLE,
+        }
+    }
+
+    const fn mair_index(self) -> u64 {
+        match self {
+            Self::Device => MemAttr::Device as u64,
+            Self::WriteBack => MemAttr::Normal as u64,
+            Self::WriteCombining | Self::Uncached => MemAttr::NormalNonCacheable as u64,
+            Self::WriteThrough => 3,
+            Self::StronglyOrdered => 4,
+        }
+    }
+}
+
+static_assertions::const_assert_eq!(MemoryType::MAIR_VALUE & 0xff_ffff, MemAttr::MAIR_VALUE);
+
+/// Returns the `MAIR_EL1` attribute selected by [`MemoryType::pte_bits`].
+const fn mair_attr(ty: MemoryType) -> u64 {
+    let index = (ty.pte_bits() >> 2) & 0b111;
+    (MemoryType::MAIR_VALUE >> (index * 8)) & 0xff
+}
+
+static_assertions::const_assert_eq!(mair_attr(MemoryType::WriteBack), 0xff);
+static_assertions::const_assert_eq!(mair_attr(MemoryType::WriteThrough), 0xbb);
+static_assertions::const_assert_eq!(mair_attr(MemoryType::WriteCombining), 0x44);
+static_assertions::const_assert_eq!(mair_attr(MemoryType::Uncached), 0x44);
+static_assertions::const_assert_eq!(mair_attr(MemoryType::Device), 0x04);
+static_assertions::const_assert_eq!(mair_attr(MemoryType::StronglyOrdered), 0x00);
@@ -1,7 +1,9 @@
 mod context;
+mod mem_type;
 
 pub mod asm;
 pub mod init;
+pub(crate) mod time;
 
 #[cfg(target_os = "none")]
 mod trap;
@@ -9,4 +11,10 @@ mod trap;
 #[cfg(feature = "uspace")]
 pub mod uspace;
 
-pub use self::context::{FpState, TaskContext, TrapFrame};
+#[cfg(feature = "uspace")]
+mod uaccess;
+
+#[cfg(all(feature = "kpti", not(feature = "arm-el2")))]
+pub mod kpti;
+
+pub use self::context::{FiberContext, FpControlState, FpState, TaskContext, TrapFrame};
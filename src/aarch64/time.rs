@@ -0,0 +1,22 @@
+//! Cycle counter based on the generic timer.
+//!
+//! The virtual count (`CNTVCT_EL0`) is used, which runs at a constant frequency
+//! (`CNTFRQ_EL0`) on all CPUs.
+
+use aarch64_cpu::{asm::barrier, registers::*};
+
+/// Returns the current value of the cycle counter (`CNTVCT_EL0`).
+///
+/// An `ISB` is executed before the read, so the counter is not read
+/// speculatively ahead of previous instructions.
+#[inline]
+pub fn cycles() -> u64 {
+    barrier::isb(barrier::SY);
+    CNTVCT_EL0.get()
+}
+
+/// Reads the frequency of the cycle counter from `CNTFRQ_EL0`, which may be
+/// zero if the firmware does not initialize it.
+pub(crate) fn detect_cycle_frequency() -> Option<u64> {
+    Some(CNTFRQ_EL0.get()).filter(|&freq| freq != 0)
+}
@@ -4,7 +4,10 @@ use tock_registers::interfaces::Readable;
 use super::TrapFrame;
 use crate::trap::PageFaultFlags;
 
-core::arch::global_asm!(include_str!("trap.S"));
+core::arch::global_asm!(
+    include_str!("trap.S"),
+    kpti = const cfg!(all(feature = "kpti", not(feature = "arm-el2"))) as u8,
+);
 
 #[repr(u8)]
 #[derive(Debug)]
@@ -0,0 +1,71 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use aarch64_cpu::registers::*;
+
+use crate::uaccess::UserAccessGuard;
+
+/// `PSTATE.PAN` in the `PAN` system register.
+const PAN_BIT: u64 = 1 << 22;
+
+static PAN_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+fn read_pan() -> bool {
+    let pan: u64;
+    // `mrs {}, PAN`
+    unsafe { asm!("mrs {}, S3_0_C4_C2_3", out(reg) pan, options(nomem, nostack)) };
+    pan & PAN_BIT != 0
+}
+
+#[inline]
+fn write_pan(enabled: bool) {
+    let pan = if enabled { PAN_BIT } else { 0 };
+    // `msr PAN, {}`
+    unsafe { asm!("msr S3_0_C4_C2_3, {}", in(reg) pan, options(nostack)) };
+}
+
+/// Enables the protection of user memory on the current CPU.
+///
+/// If PAN is supported (`ID_AA64MMFR1_EL1.PAN`), it clears `SCTLR_EL1.SPAN`
+/// so that `PSTATE.PAN` is set on exceptions taken to EL1, and sets
+/// `PSTATE.PAN`. Afterwards, the kernel cannot access user memory except in the
+/// scope of a [`UserAccessGuard`].
+///
+/// It is opt-in, and should be called after [`init_trap`] on each CPU. It
+/// does nothing if the `arm-el2` feature is enabled.
+///
+/// [`init_trap`]: crate::init::init_trap
+pub fn init_uaccess_protection() {
+    if cfg!(feature = "arm-el2") {
+        warn!("PAN is not supported with the `arm-el2` feature");
+        return;
+    }
+    if ID_AA64MMFR1_EL1.read(ID_AA64MMFR1_EL1::PAN) == 0 {
+        warn!("PAN is not supported by the CPU");
+        return;
+    }
+    const SCTLR_SPAN: u64 = 1 << 23;
+    SCTLR_EL1.set(SCTLR_EL1.get() & !SCTLR_SPAN);
+    write_pan(true);
+    PAN_ENABLED.store(true, Ordering::Release);
+}
+
+impl UserAccessGuard {
+    /// Clears `PSTATE.PAN` if PAN is enabled. Returns whether it was set
+    /// before.
+    #[inline]
+    pub(crate) fn allow_user_access() -> bool {
+        let forbidden = PAN_ENABLED.load(Ordering::Relaxed) && read_pan();
+        if forbidden {
+            write_pan(false);
+        }
+        forbidden
+    }
+
+    /// Sets `PSTATE.PAN`.
+    #[inline]
+    pub(crate) fn forbid_user_access() {
+        write_pan(true);
+    }
+}
@@ -102,9 +102,14 @@ impl UspaceContext {
                 ldp     x4, x5, [x0, 4 * 8]
                 ldp     x2, x3, [x0, 2 * 8]
                 ldp     x0, x1, [x0]
-                eret",
+            .if {kpti}
+                b       kpti_trampoline_exit
+            .else
+                eret
+            .endif",
                 in("x0") &self.0,
                 in("x1") kstack_top.as_usize() ,
+                kpti = const cfg!(all(feature = "kpti", not(feature = "arm-el2"))) as u8,
                 options(noreturn),
             )
         }
@@ -11,6 +11,14 @@ extern crate memory_addr;
 #[macro_use]
 pub mod trap;
 
+pub mod mem_type;
+pub mod task;
+pub mod time;
+pub mod tlb;
+
+#[cfg(feature = "uspace")]
+pub mod uaccess;
+
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
         mod x86_64;
@@ -2,9 +2,12 @@
 
 use core::arch::asm;
 
-use loongArch64::register::{crmd, ecfg, eentry, pgdh, pgdl};
+use loongArch64::cpu::CPUCFG;
+use loongArch64::register::{asid, crmd, ecfg, eentry, pgdh, pgdl};
 use memory_addr::{PhysAddr, VirtAddr};
 
+pub use loongArch64::register::MemoryAccessType;
+
 /// Allows the current CPU to respond to interrupts.
 #[inline]
 pub fn enable_irqs() {
@@ -110,6 +113,165 @@ pub fn flush_tlb(vaddr: Option<VirtAddr>) {
     }
 }
 
+/// Flushes the TLB entries that map the given virtual address range.
+///
+/// If `asid` is [`None`], flushes the entries of the current ASID and the
+/// global entries. Otherwise, only flushes the non-global entries of the given
+/// ASID. If the range covers more pages than [`flush_range_threshold`], the
+/// whole address space is flushed instead.
+///
+/// Only the TLB of the current CPU is flushed.
+///
+/// [`flush_range_threshold`]: crate::tlb::flush_range_threshold
+pub fn flush_tlb_range(start: VirtAddr, size: usize, asid: Option<usize>) {
+    let pages = crate::tlb::page_range(start.as_usize(), size);
+    if pages.len() > crate::tlb::flush_range_threshold() {
+        match asid {
+            Some(asid) => flush_tlb_asid(asid),
+            None => flush_tlb(None),
+        }
+        return;
+    }
+    unsafe {
+        asm!("dbar 0");
+        for page in pages {
+            let vaddr = page << 12;
+            match asid {
+                // op 0x5: Clear all page table entries with G=0 and ASID equal to the
+                // register specified ASID, and VA equal to the register specified VA.
+                Some(asid) => asm!("invtlb 0x05, {}, {}", in(reg) asid, in(reg) vaddr),
+                // op 0x6: Clear all page table entries with G=1 or ASID equal to the
+                // register specified ASID, and VA equal to the register specified VA.
+                None => asm!("invtlb 0x06, {}, {}", in(reg) asid::read().asid(), in(reg) vaddr),
+            }
+        }
+    }
+}
+
+/// Flushes all non-global TLB entries of the given ASID.
+///
+/// Only the TLB of the current CPU is flushed.
+#[inline]
+pub fn flush_tlb_asid(asid: usize) {
+    unsafe {
+        // op 0x4: Clear all page table entries with G=0 and ASID equal to the
+        // register specified ASID.
+        asm!("dbar 0; invtlb 0x04, {}, $r0", in(reg) asid);
+    }
+}
+
+/// Returns the line size in bytes of the L1 data cache, read from `CPUCFG` word
+/// 0x12.
+#[inline]
+fn dcache_line_size() -> usize {
+    1 << CPUCFG::read(0x12).get_bits(24, 30)
+}
+
+/// Performs `cacop 0x11` (hit writeback and invalidate, L1 data cache) on each
+/// line of the given range.
+fn dcache_hit_writeback_invalidate(start: VirtAddr, size: usize) {
+    let line = dcache_line_size();
+    let end = start.as_usize().saturating_add(size);
+    unsafe {
+        asm!("dbar 0");
+        for addr in (start.as_usize() & !(line - 1)..end).step_by(line) {
+            asm!("cacop 0x11, {}, 0", in(reg) addr);
+        }
+        asm!("dbar 0");
+    }
+}
+
+/// Cleans the data cache lines covering the given range, i.e., writes the
+/// dirty lines back to memory.
+///
+/// LoongArch has no clean-only operation, so the lines are also invalidated
+/// (`cacop` hit writeback and invalidate).
+#[inline]
+pub fn dcache_clean_range(start: VirtAddr, size: usize) {
+    dcache_hit_writeback_invalidate(start, size)
+}
+
+/// Invalidates the data cache lines covering the given range.
+///
+/// LoongArch writes the dirty lines back before invalidating them, so it is
+/// the same as [`dcache_clean_invalidate_range`].
+///
+/// # Safety
+///
+/// This function is unsafe for consistency with other architectures, where
+/// the dirty data in the cache lines is discarded.
+#[inline]
+pub unsafe fn dcache_invalidate_range(start: VirtAddr, size: usize) {
+    dcache_hit_writeback_invalidate(start, size)
+}
+
+/// Cleans and invalidates the data cache lines covering the given range
+/// (`cacop` hit writeback and invalidate).
+#[inline]
+pub fn dcache_clean_invalidate_range(start: VirtAddr, size: usize) {
+    dcache_hit_writeback_invalidate(start, size)
+}
+
+/// Makes the instruction fetches from the given range coherent with the
+/// previous data writes, e.g., after loading or modifying code.
+///
+/// It uses `ibar 0`, which applies to the whole instruction cache of the
+/// current CPU.
+#[inline]
+pub fn icache_sync_range(_start: VirtAddr, _size: usize) {
+    unsafe { asm!("ibar 0") }
+}
+
+/// Writes back and invalidates all data and unified caches of the current CPU
+/// by index (`cacop` index writeback and invalidate), e.g., before powering it
+/// down.
+pub fn dcache_clean_invalidate_all() {
+    // The `CPUCFG` word 0x10 describes which caches are present, and the words
+    // 0x12..=0x14 describe the L1 data cache, the L2 and L3 caches.
+    let present = CPUCFG::read(0x10);
+    let leaves = [(1, 0x12, 2), (2, 0x13, 3), (3, 0x14, 10)];
+    // The address is only used for the index and way, so the direct mapping
+    // window is used to avoid translation faults.
+    let base = (read_dmw(0).vseg as usize) << 60;
+    unsafe { asm!("dbar 0") };
+    for (leaf, cfg, present_bit) in leaves {
+        if !present.get_bit(present_bit) {
+            continue;
+        }
+        let cfg = CPUCFG::read(cfg);
+        let ways = cfg.get_bits(0, 15) + 1;
+        let sets = 1 << cfg.get_bits(16, 23);
+        let line = 1 << cfg.get_bits(24, 30);
+        for set in 0..sets {
+            for way in 0..ways {
+                let addr = base + set * line + way;
+                unsafe {
+                    match leaf {
+                        1 => asm!("cacop 0x09, {}, 0", in(reg) addr),
+                        2 => asm!("cacop 0x0a, {}, 0", in(reg) addr),
+                        _ => asm!("cacop 0x0b, {}, 0", in(reg) addr),
+                    }
+                }
+            }
+        }
+    }
+    unsafe { asm!("dbar 0") };
+}
+
+/// Invalidates all data and unified caches of the current CPU.
+///
+/// LoongArch writes the dirty lines back before invalidating them, so it is
+/// the same as [`dcache_clean_invalidate_all`].
+///
+/// # Safety
+///
+/// This function is unsafe for consistency with other architectures, where
+/// all dirty data in the caches is discarded.
+#[inline]
+pub unsafe fn dcache_invalidate_all() {
+    dcache_clean_invalidate_all()
+}
+
 /// Writes the Exception Entry Base Address register (`EENTRY`).
 ///
 /// It also set the Exception Configuration register (`ECFG`) to `VS=0`.
@@ -127,6 +289,278 @@ pub unsafe fn write_exception_entry_base(eentry: usize) {
     eentry::set_eentry(eentry);
 }
 
+macro_rules! read_csr {
+    ($csr:literal) => {{
+        let value: usize;
+        asm!(include_asm_macros!(), concat!("csrrd {}, ", $csr), out(reg) value);
+        value
+    }};
+}
+
+macro_rules! write_csr {
+    ($csr:literal, $value:expr) => {
+        asm!(include_asm_macros!(), concat!("csrwr {}, ", $csr), inout(reg) $value => _)
+    };
+}
+
+/// A TLB entry, which maps a pair of adjacent (even and odd) pages.
+///
+/// <https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#tlb-maintenance-instructions>
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TlbEntry {
+    /// The value of `TLBEHI`, i.e., the virtual page pair number (`VA[VALEN-1:13]`).
+    pub ehi: usize,
+    /// The value of `TLBELO0`, i.e., the mapping of the even page.
+    pub elo0: usize,
+    /// The value of `TLBELO1`, i.e., the mapping of the odd page.
+    pub elo1: usize,
+    /// The page size in log2 bytes (e.g., 12 for 4K pages).
+    pub ps: u8,
+    /// The ASID of the entry, which is ignored if the entry is global.
+    pub asid: u16,
+}
+
+impl TlbEntry {
+    /// Creates an entry mapping the page pair containing `vaddr`.
+    pub const fn new(vaddr: VirtAddr, ps: u8, asid: u16, elo0: usize, elo1: usize) -> Self {
+        Self {
+            ehi: vaddr.as_usize() & !((1 << (ps + 1)) - 1),
+            elo0,
+            elo1,
+            ps,
+            asid,
+        }
+    }
+
+    /// Returns the start virtual address of the page pair.
+    pub const fn vaddr(&self) -> VirtAddr {
+        va!(self.ehi & !0x1fff)
+    }
+}
+
+/// Runs `f` with IRQs disabled and the current ASID preserved, as the TLB
+/// instructions use `TLBEHI`, `TLBIDX` and `ASID` as operands.
+fn with_tlb_csrs<T>(f: impl FnOnce() -> T) -> T {
+    let irqs_enabled = irqs_enabled();
+    disable_irqs();
+    let asid = unsafe { read_csr!("LA_CSR_ASID") };
+    let ret = f();
+    unsafe { write_csr!("LA_CSR_ASID", asid) };
+    if irqs_enabled {
+        enable_irqs();
+    }
+    ret
+}
+
+/// Searches the TLB for the entry mapping `vaddr` with the given ASID
+/// (`TLBSRCH`).
+///
+/// Returns the index of the entry, or [`None`] if it is not found.
+pub fn tlb_search(vaddr: VirtAddr, asid: u16) -> Option<usize> {
+    with_tlb_csrs(|| unsafe {
+        write_csr!("LA_CSR_TLBEHI", vaddr.as_usize() & !0x1fff);
+        write_csr!("LA_CSR_ASID", asid as usize);
+        asm!("dbar 0; tlbsrch");
+        let tlbidx = read_csr!("LA_CSR_TLBIDX");
+        // TLBIDX.NE: no entry is hit.
+        (tlbidx & (1 << 31) == 0).then_some(tlbidx & 0xffff)
+    })
+}
+
+/// Reads the TLB entry at the given index (`TLBRD`).
+///
+/// Returns [`None`] if the entry is invalid.
+pub fn tlb_read(index: usize) -> Option<TlbEntry> {
+    with_tlb_csrs(|| unsafe {
+        write_csr!("LA_CSR_TLBIDX", index & 0xffff);
+        asm!("tlbrd");
+        let tlbidx = read_csr!("LA_CSR_TLBIDX");
+        if tlbidx & (1 << 31) != 0 {
+            return None;
+        }
+        Some(TlbEntry {
+            ehi: read_csr!("LA_CSR_TLBEHI"),
+            elo0: read_csr!("LA_CSR_TLBELO0"),
+            elo1: read_csr!("LA_CSR_TLBELO1"),
+            ps: ((tlbidx >> 24) & 0x3f) as u8,
+            asid: (read_csr!("LA_CSR_ASID") & 0x3ff) as u16,
+        })
+    })
+}
+
+/// Loads the entry into `TLBEHI`, `TLBELO0`, `TLBELO1`, `TLBIDX` and `ASID`.
+unsafe fn load_tlb_entry(index: usize, entry: &TlbEntry) {
+    let tlbidx = (index & 0xffff) | ((entry.ps as usize & 0x3f) << 24);
+    unsafe {
+        write_csr!("LA_CSR_TLBEHI", entry.ehi);
+        write_csr!("LA_CSR_TLBELO0", entry.elo0);
+        write_csr!("LA_CSR_TLBELO1", entry.elo1);
+        write_csr!("LA_CSR_TLBIDX", tlbidx);
+        write_csr!("LA_CSR_ASID", entry.asid as usize);
+    }
+}
+
+/// Writes the entry to the TLB at the given index (`TLBWR`).
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the address translation.
+pub unsafe fn tlb_write(index: usize, entry: &TlbEntry) {
+    with_tlb_csrs(|| unsafe {
+        load_tlb_entry(index, entry);
+        asm!("tlbwr");
+    })
+}
+
+/// Fills the entry into the TLB at a position chosen by hardware (`TLBFILL`).
+///
+/// The entry goes to the STLB if its page size equals `STLBPS`, otherwise to
+/// the MTLB.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the address translation.
+pub unsafe fn tlb_fill(entry: &TlbEntry) {
+    with_tlb_csrs(|| unsafe {
+        load_tlb_entry(0, entry);
+        asm!("tlbfill");
+    })
+}
+
+/// Configuration of a direct mapping configuration window (`DMW0`-`DMW3`).
+///
+/// In a direct mapping window, the virtual addresses whose highest 4 bits equal
+/// to `VSEG` are mapped to the physical addresses `VA[PALEN-1:0]`.
+///
+/// <https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#direct-mapping-configuration-window>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmwConfig {
+    /// The privilege levels at which the window is enabled, where bit `i`
+    /// stands for PLV`i`.
+    pub plv_mask: u8,
+    /// The memory access type of the window.
+    pub mat: MemoryAccessType,
+    /// The highest 4 bits of the virtual addresses in the window.
+    pub vseg: u8,
+}
+
+impl DmwConfig {
+    /// Creates a window enabled at PLV0 only.
+    pub const fn new(vseg: u8, mat: MemoryAccessType) -> Self {
+        Self {
+            plv_mask: 0b0001,
+            mat,
+            vseg,
+        }
+    }
+
+    /// Creates a configuration from the register value.
+    pub const fn from_bits(bits: usize) -> Self {
+        let mat = match (bits >> 4) & 0b11 {
+            0 => MemoryAccessType::StronglyOrderedUnCached,
+            1 => MemoryAccessType::CoherentCached,
+            _ => MemoryAccessType::WeaklyOrderedUnCached,
+        };
+        Self {
+            plv_mask: (bits & 0xf) as u8,
+            mat,
+            vseg: (bits >> 60) as u8,
+        }
+    }
+
+    /// Returns the register value of the configuration.
+    pub const fn bits(&self) -> usize {
+        (self.plv_mask as usize & 0xf) | ((self.mat as usize) << 4) | ((self.vseg as usize) << 60)
+    }
+
+    /// Returns whether the window is enabled at any privilege level.
+    pub const fn is_enabled(&self) -> bool {
+        self.plv_mask & 0xf != 0
+    }
+
+    /// Returns whether the given virtual address is in the window.
+    pub const fn contains(&self, vaddr: VirtAddr) -> bool {
+        (vaddr.as_usize() >> 60) as u8 == self.vseg
+    }
+
+    /// Converts the physical address to the virtual address in the window.
+    pub const fn phys_to_virt(&self, paddr: PhysAddr) -> VirtAddr {
+        va!(paddr.as_usize() | ((self.vseg as usize) << 60))
+    }
+
+    /// Converts the virtual address in the window to the physical address.
+    ///
+    /// Returns [`None`] if the address is not in the window.
+    pub fn virt_to_phys(&self, vaddr: VirtAddr) -> Option<PhysAddr> {
+        let palen = loongArch64::cpu::get_palen();
+        self.contains(vaddr)
+            .then(|| pa!(vaddr.as_usize() & ((1 << palen) - 1)))
+    }
+}
+
+/// Reads the direct mapping configuration window register `DMW<index>`.
+///
+/// # Panics
+///
+/// Panics if `index` is not in `0..4`.
+#[inline]
+pub fn read_dmw(index: usize) -> DmwConfig {
+    let bits = unsafe {
+        match index {
+            0 => read_csr!("LA_CSR_DMW0"),
+            1 => read_csr!("LA_CSR_DMW1"),
+            2 => read_csr!("LA_CSR_DMW2"),
+            3 => read_csr!("LA_CSR_DMW3"),
+            _ => panic!("invalid DMW index: {index}"),
+        }
+    };
+    DmwConfig::from_bits(bits)
+}
+
+/// Writes the direct mapping configuration window register `DMW<index>`.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the address translation of the
+/// window.
+///
+/// # Panics
+///
+/// Panics if `index` is not in `0..4`.
+#[inline]
+pub unsafe fn write_dmw(index: usize, config: DmwConfig) {
+    let bits = config.bits();
+    unsafe {
+        match index {
+            0 => write_csr!("LA_CSR_DMW0", bits),
+            1 => write_csr!("LA_CSR_DMW1", bits),
+            2 => write_csr!("LA_CSR_DMW2", bits),
+            3 => write_csr!("LA_CSR_DMW3", bits),
+            _ => panic!("invalid DMW index: {index}"),
+        }
+    }
+}
+
+/// Converts the physical address to the virtual address in the first enabled
+/// direct mapping window with the given memory access type.
+///
+/// Returns [`None`] if no such window is configured.
+pub fn dmw_phys_to_virt(paddr: PhysAddr, mat: MemoryAccessType) -> Option<VirtAddr> {
+    (0..4)
+        .map(read_dmw)
+        .find(|dmw| dmw.is_enabled() && dmw.mat == mat)
+        .map(|dmw| dmw.phys_to_virt(paddr))
+}
+
+/// Converts the virtual address to the physical address, if it is in any
+/// enabled direct mapping window.
+pub fn dmw_virt_to_phys(vaddr: VirtAddr) -> Option<PhysAddr> {
+    (0..4)
+        .map(read_dmw)
+        .filter(|dmw| dmw.is_enabled())
+        .find_map(|dmw| dmw.virt_to_phys(vaddr))
+}
+
 /// Writes the Page Walk Controller registers (`PWCL` and `PWCH`).
 ///
 /// # Safety
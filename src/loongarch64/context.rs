@@ -69,6 +69,43 @@ impl FpuState {
     }
 }
 
+/// Floating-point control and status register of a task.
+///
+/// It is a lightweight alternative of [`FpuState`], which only preserves
+/// `fcsr0` across context switches (the `fp-ctrl` feature without `fp-simd`),
+/// so that the rounding mode of a task does not leak into others.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FpControlState {
+    /// Floating-point Control and Status register
+    pub fcsr: u32,
+}
+
+#[cfg(feature = "fp-ctrl")]
+impl FpControlState {
+    /// Saves the current `fcsr0` from CPU to this structure.
+    ///
+    /// Nothing is saved if FP instructions are disabled (`EUEN.FPE` is 0).
+    #[inline]
+    pub fn save(&mut self) {
+        if loongArch64::register::euen::read().fpe() {
+            let fcsr: usize;
+            unsafe { core::arch::asm!("movfcsr2gr {}, $fcsr0", out(reg) fcsr) };
+            self.fcsr = fcsr as _;
+        }
+    }
+
+    /// Restores `fcsr0` from this structure to CPU.
+    ///
+    /// Nothing is restored if FP instructions are disabled (`EUEN.FPE` is 0).
+    #[inline]
+    pub fn restore(&self) {
+        if loongArch64::register::euen::read().fpe() {
+            unsafe { core::arch::asm!("movgr2fcsr $fcsr0, {}", in(reg) self.fcsr as usize) };
+        }
+    }
+}
+
 /// Saved registers when a trap (interrupt or exception) occurs.
 #[repr(C)]
 #[derive(Debug, Default, Clone, Copy)]
@@ -142,6 +179,10 @@ pub struct TaskContext {
     #[cfg(feature = "fp-simd")]
     /// Floating Point Unit states
     pub fpu: FpuState,
+    #[cfg(all(feature = "fp-ctrl", not(feature = "fp-simd")))]
+    /// FP control and status register, when the full FPU states are not
+    /// switched.
+    pub fp_ctrl: FpControlState,
 }
 
 impl TaskContext {
@@ -152,12 +193,37 @@ impl TaskContext {
 
     /// Initializes the context for a new task, with the given entry point and
     /// kernel stack.
+    ///
+    /// The entry function must never return, see [`init_with_arg`] otherwise.
+    ///
+    /// [`init_with_arg`]: TaskContext::init_with_arg
     pub fn init(&mut self, entry: usize, kstack_top: VirtAddr, tls_area: VirtAddr) {
         self.sp = kstack_top.as_usize();
         self.ra = entry;
         self.tp = tls_area.as_usize();
     }
 
+    /// Initializes the context for a new task, which calls `entry(arg)` on the
+    /// given kernel stack.
+    ///
+    /// If the task is first entered by
+    /// [`switch_to_irqsave`](Self::switch_to_irqsave), the hook registered to
+    /// [`FINISH_SWITCH`](crate::task::FINISH_SWITCH) is called and IRQs are
+    /// enabled before the entry function. If the entry function returns, the
+    /// hook registered to
+    /// [`TASK_EXIT`](crate::task::TASK_EXIT) is called on the same stack.
+    pub fn init_with_arg(
+        &mut self,
+        entry: usize,
+        arg: usize,
+        kstack_top: VirtAddr,
+        tls_area: VirtAddr,
+    ) {
+        self.init(task_entry as *const () as _, kstack_top, tls_area);
+        self.s[0] = entry;
+        self.s[1] = arg;
+    }
+
     /// Changes the page table root in this context.
     ///
     /// The hardware register for user page table root (`pgdl` for loongarch64)
@@ -171,7 +237,26 @@ impl TaskContext {
     ///
     /// It first saves the current task's context from CPU to this place, and then
     /// restores the next task's context from `next_ctx` to CPU.
+    ///
+    /// It does not change the IRQ state. However, if the current task is
+    /// switched back by [`switch_to_irqsave`](Self::switch_to_irqsave), the
+    /// hook registered to [`FINISH_SWITCH`](crate::task::FINISH_SWITCH) is
+    /// called, and IRQs are restored to the state before this function was
+    /// called.
     pub fn switch_to(&mut self, next_ctx: &Self) {
+        let irqs_were_enabled = crate::asm::irqs_enabled();
+        if self.switch_to_impl(next_ctx, false) {
+            crate::task::finish_switch(irqs_were_enabled);
+        }
+    }
+
+    /// Switches to another task, where `irqsave` is passed to the next task,
+    /// i.e., to the entry trampoline if it is newly created, or as the return
+    /// value of this function in the next task otherwise.
+    ///
+    /// Returns the `irqsave` argument of the switch that resumes the current
+    /// task (`false` if resumed by [`Self::load`]).
+    pub(crate) fn switch_to_impl(&mut self, next_ctx: &Self, irqsave: bool) -> bool {
         #[cfg(feature = "tls")]
         {
             self.tp = crate::asm::read_thread_pointer();
@@ -179,6 +264,8 @@ impl TaskContext {
         }
         #[cfg(feature = "uspace")]
         {
+            #[cfg(feature = "tlb-shootdown")]
+            crate::tlb::set_active_root(pa!(next_ctx.pgdl));
             if self.pgdl != next_ctx.pgdl {
                 unsafe { crate::asm::write_user_page_table(pa!(next_ctx.pgdl)) };
                 crate::asm::flush_tlb(None); // currently flush the entire TLB
@@ -189,7 +276,64 @@ impl TaskContext {
             self.fpu.save();
             next_ctx.fpu.restore();
         }
-        unsafe { context_switch(self, next_ctx) }
+        #[cfg(all(feature = "fp-ctrl", not(feature = "fp-simd")))]
+        {
+            self.fp_ctrl.save();
+            next_ctx.fp_ctrl.restore();
+        }
+        unsafe { context_switch(self, next_ctx, irqsave) }
+    }
+
+    /// Restores this context to CPU and jumps to it, without saving the
+    /// current context.
+    ///
+    /// It can be used to enter the first task on a CPU.
+    ///
+    /// # Safety
+    ///
+    /// The context must be initialized by [`init`] or saved by [`switch_to`].
+    /// The current stack is abandoned.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`switch_to`]: TaskContext::switch_to
+    pub unsafe fn load(&self) -> ! {
+        unsafe { self.load_from(None) }
+    }
+
+    /// Switches to another task without saving the current task's context.
+    ///
+    /// It can be used when the current task is exiting, as the current stack
+    /// is never touched after switching, so it can be freed by the next task.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`load`](TaskContext::load).
+    pub unsafe fn switch_to_no_save(&self, next_ctx: &Self) -> ! {
+        unsafe { next_ctx.load_from(Some(self)) }
+    }
+
+    #[cfg_attr(not(feature = "uspace"), allow(unused_variables))]
+    unsafe fn load_from(&self, prev: Option<&Self>) -> ! {
+        #[cfg(feature = "tls")]
+        unsafe {
+            crate::asm::write_thread_pointer(self.tp);
+        }
+        #[cfg(feature = "uspace")]
+        {
+            let cur_root =
+                prev.map_or_else(|| crate::asm::read_user_page_table().as_usize(), |p| p.pgdl);
+            #[cfg(feature = "tlb-shootdown")]
+            crate::tlb::set_active_root(pa!(self.pgdl));
+            if self.pgdl != cur_root {
+                unsafe { crate::asm::write_user_page_table(pa!(self.pgdl)) };
+                crate::asm::flush_tlb(None);
+            }
+        }
+        #[cfg(feature = "fp-simd")]
+        self.fpu.restore();
+        #[cfg(all(feature = "fp-ctrl", not(feature = "fp-simd")))]
+        self.fp_ctrl.restore();
+        unsafe { context_load(self) }
     }
 }
 
@@ -227,8 +371,126 @@ unsafe extern "C" fn restore_fp_registers(fpu: &FpuState) {
     )
 }
 
+/// A minimal context for stackful coroutines (fibers).
+///
+/// Unlike [`TaskContext`], it only holds the return address, the stack pointer
+/// and the callee-saved registers. FP states, the thread pointer and the page
+/// table root are not touched.
+///
+/// When the entry function of a fiber returns, the fiber switches back to the
+/// context that most recently switched to it.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct FiberContext {
+    // The same layout as the beginning of `TaskContext`, so that they can share
+    // the `context_switch` routine.
+    ra: usize,
+    sp: usize,
+    s: [usize; 10], // s0..s8, fp
+    /// Slot on the fiber stack that records the context to resume when the
+    /// fiber finishes.
+    resume_slot: usize,
+}
+
+static_assertions::const_assert_eq!(
+    core::mem::offset_of!(FiberContext, resume_slot),
+    core::mem::offset_of!(TaskContext, tp)
+);
+
+impl FiberContext {
+    /// Creates an empty context.
+    ///
+    /// It can be used as the context of the caller that starts fibers, which
+    /// will be filled by [`switch`](FiberContext::switch).
+    pub const fn new() -> Self {
+        Self {
+            ra: 0,
+            sp: 0,
+            s: [0; 10],
+            resume_slot: 0,
+        }
+    }
+
+    /// Initializes the context to run `entry(arg)` on the given stack.
+    ///
+    /// # Safety
+    ///
+    /// `stack_top` must be the top of a stack that is valid for writes and
+    /// large enough for `entry`. The stack must not be used for other purposes
+    /// until the fiber finishes.
+    pub unsafe fn make_context(
+        &mut self,
+        stack_top: VirtAddr,
+        entry: extern "C" fn(usize),
+        arg: usize,
+    ) {
+        let resume_slot = stack_top.as_usize() - 16;
+        unsafe { (resume_slot as *mut usize).write(0) };
+        *self = Self {
+            ra: entry_trampoline as *const () as _,
+            sp: resume_slot,
+            resume_slot,
+            ..Self::new()
+        };
+        self.s[0] = entry as usize;
+        self.s[1] = arg;
+        self.s[2] = fiber_exit as *const () as _;
+        self.s[3] = resume_slot;
+    }
+
+    /// Switches to another fiber.
+    ///
+    /// It saves the callee-saved registers to this place, and then restores
+    /// the ones of `next_ctx`.
+    pub fn switch(&mut self, next_ctx: &Self) {
+        if next_ctx.resume_slot != 0 {
+            unsafe { (next_ctx.resume_slot as *mut usize).write(self as *mut _ as usize) };
+        }
+        unsafe { context_switch(self as *mut _ as _, next_ctx as *const _ as _, false) };
+    }
+}
+
+/// Resumes the context recorded in `resume_slot` after a fiber finishes.
+unsafe extern "C" fn fiber_exit(resume_slot: *const *const FiberContext) -> ! {
+    unsafe { context_load(*resume_slot as _) }
+}
+
+/// Calls `s0(s1)`, then `s2(s3)` which must not return.
+#[unsafe(naked)]
+unsafe extern "C" fn entry_trampoline() -> ! {
+    naked_asm!(
+        "
+        move    $a0, $s1
+        jirl    $ra, $s0, 0
+        move    $a0, $s3
+        jirl    $ra, $s2, 0
+        break   0",
+    )
+}
+
+/// Finishes the context switch with the `irqsave` argument of `context_switch`
+/// left in `a2` (cleared by `context_load`), then calls `s0(s1)` and exits the task.
 #[unsafe(naked)]
-unsafe extern "C" fn context_switch(_current_task: &mut TaskContext, _next_task: &TaskContext) {
+unsafe extern "C" fn task_entry() -> ! {
+    naked_asm!(
+        "
+        move    $a0, $a2
+        bl      {finish_switch}
+        move    $a0, $s1
+        jirl    $ra, $s0, 0
+        bl      {task_exit}
+        break   0",
+        finish_switch = sym crate::task::task_entry_finish_switch,
+        task_exit = sym crate::task::task_exit,
+    )
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn context_switch(
+    _current_task: *mut TaskContext,
+    _next_task: *const TaskContext,
+    _irqsave: bool,
+) -> bool {
     naked_asm!(
         include_asm_macros!(),
         "
@@ -260,6 +522,31 @@ unsafe extern "C" fn context_switch(_current_task: &mut TaskContext, _next_task:
         LDD     $sp, $a1, 1
         LDD     $ra, $a1, 0
 
+        move    $a0, $a2
+        ret",
+    )
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn context_load(_next_task: *const TaskContext) -> ! {
+    naked_asm!(
+        include_asm_macros!(),
+        "
+        move    $a2, $zero
+        LDD     $fp, $a0, 11
+        LDD     $s8, $a0, 10
+        LDD     $s7, $a0, 9
+        LDD     $s6, $a0, 8
+        LDD     $s5, $a0, 7
+        LDD     $s4, $a0, 6
+        LDD     $s3, $a0, 5
+        LDD     $s2, $a0, 4
+        LDD     $s1, $a0, 3
+        LDD     $s0, $a0, 2
+        LDD     $sp, $a0, 1
+        LDD     $ra, $a0, 0
+
+        move    $a0, $zero
         ret",
     )
 }
@@ -4,29 +4,111 @@ use loongArch64::register::{crmd, stlbps, tlbidx, tlbrehi, tlbrentry};
 use memory_addr::PhysAddr;
 use page_table_multiarch::loongarch64::LA64MetaData;
 
+#[cfg(feature = "uspace")]
+pub use super::uaccess::init_uaccess_protection;
+
+/// Base page sizes supported by the MMU.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// 4 KiB pages, with 4-level page tables.
+    Size4K = 12,
+    /// 16 KiB pages, with 3-level page tables.
+    Size16K = 14,
+    /// 64 KiB pages, with 3-level page tables.
+    Size64K = 16,
+}
+
+impl PageSize {
+    /// Returns the page size in bytes.
+    pub const fn size(self) -> usize {
+        1 << self as usize
+    }
+
+    /// Returns the number of page table levels.
+    ///
+    /// Each page table occupies one page with 8-byte entries. The 16K and 64K
+    /// pages use 3 levels, as `PWCL.Dir2Base` cannot hold their bases.
+    pub const fn levels(self) -> usize {
+        match self {
+            Self::Size4K => 4,
+            Self::Size16K | Self::Size64K => 3,
+        }
+    }
+
+    /// Returns the values of the Page Walk Controller registers (`PWCL`,
+    /// `PWCH`) for page tables of this page size.
+    ///
+    /// The root page table is always walked as `Dir3` by the TLB refill
+    /// handler, and `Dir2` is only used by 4-level page tables.
+    pub const fn pwc_values(self) -> (u32, u32) {
+        let ps = self as u32;
+        let width = ps - 3;
+        let dir1_base = ps + width;
+        let (dir2_base, dir2_width, dir3_base) = match self {
+            Self::Size4K => (dir1_base + width, width, dir1_base + 2 * width),
+            Self::Size16K | Self::Size64K => (0, 0, dir1_base + width),
+        };
+        let pwcl = ps
+            | (width << 5)
+            | (dir1_base << 10)
+            | (width << 15)
+            | (dir2_base << 20)
+            | (dir2_width << 25);
+        let pwch = dir3_base | (width << 6);
+        (pwcl, pwch)
+    }
+}
+
+// The 4K page tables are compatible with `page_table_multiarch`.
+static_assertions::const_assert_eq!(PageSize::Size4K.pwc_values().0, LA64MetaData::PWCL_VALUE);
+static_assertions::const_assert_eq!(PageSize::Size4K.pwc_values().1, LA64MetaData::PWCH_VALUE);
+
 /// Initializes TLB and MMU related registers on the current CPU.
 ///
 /// It sets the TLB Refill exception entry (`TLBRENTY`), page table root address,
 /// and finally enables the mapped address translation mode.
 ///
+/// The base page size is 4K. See [`init_mmu_with_page_size`] for other page
+/// sizes.
+///
 /// - TLBRENTY: <https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#tlb-refill-exception-entry-base-address>
 /// - CRMD: <https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#current-mode-information>
 pub fn init_mmu(root_paddr: PhysAddr, phys_virt_offset: usize) {
+    init_mmu_with_page_size(root_paddr, phys_virt_offset, PageSize::Size4K);
+}
+
+/// Initializes TLB and MMU related registers on the current CPU, with the
+/// given base page size.
+///
+/// The page tables must be built with the layout described by
+/// [`PageSize::levels`] and [`PageSize::pwc_values`]. Huge pages (with bit 6
+/// set in a directory entry) are supported by the TLB refill handler, and are
+/// filled with their own page sizes.
+///
+/// See [`init_mmu`] for details.
+pub fn init_mmu_with_page_size(root_paddr: PhysAddr, phys_virt_offset: usize, page_size: PageSize) {
     unsafe extern "C" {
         fn handle_tlb_refill();
+        fn handle_tlb_refill_3level();
     }
 
     // Configure TLB
-    const PS_4K: usize = 0x0c; // Page Size 4KB
-    let tlbrentry_paddr = pa!(handle_tlb_refill as usize - phys_virt_offset);
-    tlbidx::set_ps(PS_4K);
-    stlbps::set_ps(PS_4K);
-    tlbrehi::set_ps(PS_4K);
+    let ps = page_size as usize;
+    let handler = match page_size.levels() {
+        4 => handle_tlb_refill as *const (),
+        _ => handle_tlb_refill_3level as *const (),
+    };
+    let tlbrentry_paddr = pa!(handler as usize - phys_virt_offset);
+    tlbidx::set_ps(ps);
+    stlbps::set_ps(ps);
+    tlbrehi::set_ps(ps);
     tlbrentry::set_tlbrentry(tlbrentry_paddr.as_usize());
 
     // Configure page table walking
+    let (pwcl, pwch) = page_size.pwc_values();
     unsafe {
-        crate::asm::write_pwc(LA64MetaData::PWCL_VALUE, LA64MetaData::PWCH_VALUE);
+        crate::asm::write_pwc(pwcl, pwch);
         crate::asm::write_kernel_page_table(root_paddr);
         crate::asm::write_user_page_table(pa!(0));
     }
@@ -39,11 +121,20 @@ pub fn init_mmu(root_paddr: PhysAddr, phys_virt_offset: usize) {
 /// Initializes trap handling on the current CPU.
 ///
 /// In detail, it initializes the exception vector on LoongArch64 platforms.
+///
+/// If the `tlb-shootdown` feature is enabled, it also marks the current CPU as
+/// online ([`mark_current_cpu_online`]), so that it receives TLB shootdowns.
+/// The [`CURRENT_CPU_ID`] hook must be registered in that case.
+///
+/// [`mark_current_cpu_online`]: crate::tlb::mark_current_cpu_online
+/// [`CURRENT_CPU_ID`]: crate::tlb::CURRENT_CPU_ID
 pub fn init_trap() {
     unsafe extern "C" {
         fn exception_entry_base();
     }
     unsafe {
-        crate::asm::write_exception_entry_base(exception_entry_base as usize);
+        crate::asm::write_exception_entry_base(exception_entry_base as *const () as usize);
     }
+    #[cfg(feature = "tlb-shootdown")]
+    crate::tlb::mark_current_cpu_online();
 }
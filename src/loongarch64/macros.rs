@@ -8,6 +8,11 @@ macro_rules! include_asm_macros {
         .equ LA_CSR_PRMD,          0x1
         .equ LA_CSR_EUEN,          0x2
         .equ LA_CSR_ERA,           0x6
+        .equ LA_CSR_TLBIDX,        0x10    // TLB index
+        .equ LA_CSR_TLBEHI,        0x11    // TLB entry high-order bits
+        .equ LA_CSR_TLBELO0,       0x12    // TLB entry low-order bits (even page)
+        .equ LA_CSR_TLBELO1,       0x13    // TLB entry low-order bits (odd page)
+        .equ LA_CSR_ASID,          0x18
         .equ LA_CSR_PGDL,          0x19    // Page table base address when VA[47] = 0
         .equ LA_CSR_PGDH,          0x1a    // Page table base address when VA[47] = 1
         .equ LA_CSR_PGD,           0x1b    // Page table base
@@ -22,6 +27,8 @@ macro_rules! include_asm_macros {
         .equ LA_CSR_TLBREHI,       0x8e    // TLB refill entryhi
         .equ LA_CSR_DMW0,          0x180
         .equ LA_CSR_DMW1,          0x181
+        .equ LA_CSR_DMW2,          0x182
+        .equ LA_CSR_DMW3,          0x183
 
         .equ KSAVE_KSP,            0x30
         .equ KSAVE_TEMP,           0x31
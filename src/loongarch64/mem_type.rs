@@ -0,0 +1,25 @@
+use crate::asm::MemoryAccessType;
+use crate::mem_type::MemoryType;
+
+impl MemoryType {
+    /// Returns the memory access type (MAT), which is also used by the direct
+    /// mapping windows.
+    ///
+    /// Write-through and write-combining memory fall back to weakly-ordered
+    /// uncached (`WUC`), and other non-cacheable memory is strongly-ordered
+    /// uncached (`SUC`).
+    pub const fn mat(self) -> MemoryAccessType {
+        match self {
+            Self::WriteBack => MemoryAccessType::CoherentCached,
+            Self::WriteThrough | Self::WriteCombining => MemoryAccessType::WeaklyOrderedUnCached,
+            Self::Uncached | Self::Device | Self::StronglyOrdered => {
+                MemoryAccessType::StronglyOrderedUnCached
+            }
+        }
+    }
+
+    /// Returns the attribute bits (`MAT`, bits 5:4) of page table entries.
+    pub const fn pte_bits(self) -> u64 {
+        (self.mat() as u64) << 4
+    }
+}
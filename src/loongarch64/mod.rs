@@ -2,12 +2,19 @@
 mod macros;
 
 mod context;
+mod mem_type;
 mod trap;
 
 pub mod asm;
 pub mod init;
+pub(crate) mod time;
 
 #[cfg(feature = "uspace")]
 pub mod uspace;
 
-pub use self::context::{FpuState, GeneralRegisters, TaskContext, TrapFrame};
+#[cfg(feature = "uspace")]
+mod uaccess;
+
+pub use self::context::{
+    FiberContext, FpControlState, FpuState, GeneralRegisters, TaskContext, TrapFrame,
+};
@@ -0,0 +1,28 @@
+//! Cycle counter based on the stable counter.
+//!
+//! The stable counter, read by `rdtime.d`, runs at a constant frequency on all
+//! CPUs. The frequency is described by the `CPUCFG` words 4 and 5.
+
+use loongArch64::cpu::CPUCFG;
+
+/// Returns whether the constant frequency stable counter is supported
+/// (`CPUCFG.2.LLFTP[bit 14]`).
+#[inline]
+pub fn has_stable_counter() -> bool {
+    CPUCFG::read(2).get_bit(14)
+}
+
+/// Returns the current value of the cycle counter (`rdtime.d`).
+#[inline]
+pub fn cycles() -> u64 {
+    loongArch64::time::Time::read() as u64
+}
+
+/// Computes the frequency of the cycle counter as the crystal frequency
+/// (`CPUCFG` word 4) multiplied by the ratio in `CPUCFG` word 5.
+pub(crate) fn detect_cycle_frequency() -> Option<u64> {
+    let base = CPUCFG::read(4).get_bits(0, 31) as u64;
+    let cfg = CPUCFG::read(5);
+    let (mul, div) = (cfg.get_bits(0, 15) as u64, cfg.get_bits(16, 31) as u64);
+    (base * mul).checked_div(div).filter(|&freq| freq != 0)
+}
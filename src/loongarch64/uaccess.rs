@@ -0,0 +1,20 @@
+use crate::uaccess::UserAccessGuard;
+
+/// Enables the protection of user memory on the current CPU.
+///
+/// LoongArch has no mechanism to prevent the kernel from accessing user
+/// memory, so it does nothing. It is provided for consistency with other
+/// architectures.
+pub fn init_uaccess_protection() {}
+
+impl UserAccessGuard {
+    /// Does nothing, since user memory is always accessible.
+    #[inline]
+    pub(crate) fn allow_user_access() -> bool {
+        false
+    }
+
+    /// Does nothing, since user memory is always accessible.
+    #[inline]
+    pub(crate) fn forbid_user_access() {}
+}
@@ -0,0 +1,27 @@
+//! Memory types (cacheability and ordering attributes) of mappings.
+//!
+//! [`MemoryType`] provides the attribute bits of page table entries on each
+//! architecture, e.g., [`MemoryType::pte_bits`].
+
+/// Memory types of mappings, which are translated to the page attribute table
+/// (PAT) entries on x86_64, the `MAIR_EL1` indices on AArch64, the Svpbmt
+/// types on RISC-V, and the memory access types (MAT) on LoongArch64.
+///
+/// The types not supported by an architecture fall back to a stricter one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Normal write-back cacheable memory.
+    WriteBack,
+    /// Normal write-through cacheable memory.
+    WriteThrough,
+    /// Normal non-cacheable memory which allows write combining, e.g., for
+    /// framebuffers.
+    WriteCombining,
+    /// Normal non-cacheable memory.
+    Uncached,
+    /// Device memory, which allows early write acknowledgement (e.g.,
+    /// Device-nGnRE on AArch64).
+    Device,
+    /// Strongly-ordered device memory (e.g., Device-nGnRnE on AArch64).
+    StronglyOrdered,
+}
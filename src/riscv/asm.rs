@@ -1,5 +1,7 @@
 //! Wrapper functions for assembly instructions.
 
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use memory_addr::{PhysAddr, VirtAddr};
 use riscv::asm;
 use riscv::register::{satp, sstatus, stvec};
@@ -37,6 +39,96 @@ pub fn halt() {
     riscv::asm::wfi() // should never return
 }
 
+/// Page-based virtual-memory schemes, i.e., the `MODE` field of `satp`.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    /// Page-based 32-bit virtual addressing, with 2-level page tables.
+    #[cfg(target_pointer_width = "32")]
+    Sv32 = 1,
+    /// Page-based 39-bit virtual addressing, with 3-level page tables.
+    #[cfg(target_pointer_width = "64")]
+    Sv39 = 8,
+    /// Page-based 48-bit virtual addressing, with 4-level page tables.
+    #[cfg(target_pointer_width = "64")]
+    Sv48 = 9,
+    /// Page-based 57-bit virtual addressing, with 5-level page tables.
+    #[cfg(target_pointer_width = "64")]
+    Sv57 = 10,
+}
+
+impl PagingMode {
+    /// The position of the `MODE` field in `satp`.
+    #[cfg(target_pointer_width = "32")]
+    const SATP_SHIFT: usize = 31;
+    #[cfg(target_pointer_width = "64")]
+    const SATP_SHIFT: usize = 60;
+
+    /// The mode used if the address translation is not enabled yet.
+    #[cfg(target_pointer_width = "32")]
+    const DEFAULT: Self = Self::Sv32;
+    #[cfg(target_pointer_width = "64")]
+    const DEFAULT: Self = Self::Sv39;
+
+    /// Returns the number of page table levels.
+    pub const fn levels(self) -> usize {
+        match self {
+            #[cfg(target_pointer_width = "32")]
+            Self::Sv32 => 2,
+            #[cfg(target_pointer_width = "64")]
+            Self::Sv39 => 3,
+            #[cfg(target_pointer_width = "64")]
+            Self::Sv48 => 4,
+            #[cfg(target_pointer_width = "64")]
+            Self::Sv57 => 5,
+        }
+    }
+
+    /// Returns the number of valid virtual address bits.
+    pub const fn va_bits(self) -> usize {
+        // each level translates 10 bits in Sv32, or 9 bits in other modes
+        let bits_per_level = if cfg!(target_pointer_width = "32") {
+            10
+        } else {
+            9
+        };
+        12 + bits_per_level * self.levels()
+    }
+
+    const fn from_satp(satp: usize) -> Option<Self> {
+        match satp >> Self::SATP_SHIFT {
+            #[cfg(target_pointer_width = "32")]
+            1 => Some(Self::Sv32),
+            #[cfg(target_pointer_width = "64")]
+            8 => Some(Self::Sv39),
+            #[cfg(target_pointer_width = "64")]
+            9 => Some(Self::Sv48),
+            #[cfg(target_pointer_width = "64")]
+            10 => Some(Self::Sv57),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the paging mode of the current CPU from `satp`.
+///
+/// Returns [`None`] if the address translation is not enabled.
+#[inline]
+pub fn read_paging_mode() -> Option<PagingMode> {
+    PagingMode::from_satp(satp::read().bits())
+}
+
+/// Writes `satp` with the given paging mode and page table root, using ASID 0.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the virtual memory address space.
+#[inline]
+pub(crate) unsafe fn write_satp(mode: PagingMode, root_paddr: PhysAddr) {
+    let bits = ((mode as usize) << PagingMode::SATP_SHIFT) | (root_paddr.as_usize() >> 12);
+    unsafe { satp::write(satp::Satp::from_bits(bits)) };
+}
+
 /// Reads the current page table root register for user space (`satp`).
 ///
 /// RISC-V does not have a separate page table root register for user and
@@ -65,6 +157,10 @@ pub fn read_kernel_page_table() -> PhysAddr {
 /// RISC-V does not have a separate page table root register for user
 /// and kernel space, so this operation is the same as [`write_kernel_page_table`].
 ///
+/// The paging mode of the current CPU is kept unchanged, or Sv39 (Sv32 on
+/// riscv32) is used if the address translation is not enabled yet. Use
+/// [`init_mmu`](crate::init::init_mmu) to choose another mode.
+///
 /// Note that the TLB is **NOT** flushed after this operation.
 ///
 /// # Safety
@@ -72,7 +168,8 @@ pub fn read_kernel_page_table() -> PhysAddr {
 /// This function is unsafe as it changes the virtual memory address space.
 #[inline]
 pub unsafe fn write_user_page_table(root_paddr: PhysAddr) {
-    unsafe { satp::set(satp::Mode::Sv39, 0, root_paddr.as_usize() >> 12) };
+    let mode = read_paging_mode().unwrap_or(PagingMode::DEFAULT);
+    unsafe { write_satp(mode, root_paddr) };
 }
 
 /// Writes the register to update the current page table root for user space
@@ -104,6 +201,139 @@ pub fn flush_tlb(vaddr: Option<VirtAddr>) {
     }
 }
 
+/// Flushes the TLB entries that map the given virtual address range.
+///
+/// If `asid` is [`None`], flushes the entries of all address spaces. Otherwise,
+/// only flushes the non-global entries of the given ASID. If the range covers
+/// more pages than [`flush_range_threshold`], the whole address space is
+/// flushed instead.
+///
+/// Only the TLB of the current CPU is flushed.
+///
+/// [`flush_range_threshold`]: crate::tlb::flush_range_threshold
+pub fn flush_tlb_range(start: VirtAddr, size: usize, asid: Option<usize>) {
+    let pages = crate::tlb::page_range(start.as_usize(), size);
+    if pages.len() > crate::tlb::flush_range_threshold() {
+        match asid {
+            Some(asid) => flush_tlb_asid(asid),
+            None => asm::sfence_vma_all(),
+        }
+        return;
+    }
+    for page in pages {
+        let vaddr = page << 12;
+        match asid {
+            Some(asid) => asm::sfence_vma(asid, vaddr),
+            None => unsafe { core::arch::asm!("sfence.vma {}, zero", in(reg) vaddr) },
+        }
+    }
+}
+
+/// Flushes all non-global TLB entries of the given ASID.
+///
+/// Only the TLB of the current CPU is flushed.
+#[inline]
+pub fn flush_tlb_asid(asid: usize) {
+    unsafe { core::arch::asm!("sfence.vma zero, {}", in(reg) asid) }
+}
+
+static CBOM_BLOCK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the cache block size in bytes for the Zicbom extension, which is
+/// usually read from the `riscv,cbom-block-size` property of the device tree.
+///
+/// The data cache maintenance functions (e.g., [`dcache_clean_range`]) are
+/// no-ops until it is set to a nonzero value, which is correct for the
+/// platforms without Zicbom, whose caches are coherent with DMA.
+pub fn set_cbom_block_size(size: usize) {
+    assert!(size == 0 || size.is_power_of_two());
+    CBOM_BLOCK_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// Performs the Zicbom cache-block operation `funct` on each block of the
+/// given range.
+///
+/// The operations are encoded by `.insn`, as they require no Zicbom support
+/// from the assembler.
+macro_rules! cbo_range_op {
+    ($funct:literal, $start:expr, $size:expr) => {{
+        let block = CBOM_BLOCK_SIZE.load(Ordering::Relaxed);
+        if block != 0 {
+            let start = $start.as_usize() & !(block - 1);
+            let end = $start.as_usize().saturating_add($size);
+            core::arch::asm!("fence rw, rw");
+            for addr in (start..end).step_by(block) {
+                core::arch::asm!(concat!(".insn i 0x0f, 2, x0, {}, ", $funct), in(reg) addr);
+            }
+            core::arch::asm!("fence rw, rw");
+        }
+    }};
+}
+
+/// Cleans the data cache blocks covering the given range (`cbo.clean`), i.e.,
+/// writes the dirty blocks back to memory.
+///
+/// It is used before a device reads the memory by DMA.
+#[inline]
+pub fn dcache_clean_range(start: VirtAddr, size: usize) {
+    unsafe { cbo_range_op!(1, start, size) }
+}
+
+/// Invalidates the data cache blocks covering the given range (`cbo.inval`).
+///
+/// It is used after a device writes the memory by DMA. The implementation may
+/// write the dirty blocks back before invalidating them.
+///
+/// # Safety
+///
+/// This function is unsafe as the dirty data in the cache blocks may be
+/// discarded, including data outside the range but in the same blocks.
+#[inline]
+pub unsafe fn dcache_invalidate_range(start: VirtAddr, size: usize) {
+    unsafe { cbo_range_op!(0, start, size) }
+}
+
+/// Cleans and invalidates the data cache blocks covering the given range
+/// (`cbo.flush`).
+#[inline]
+pub fn dcache_clean_invalidate_range(start: VirtAddr, size: usize) {
+    unsafe { cbo_range_op!(2, start, size) }
+}
+
+/// Makes the instruction fetches from the given range coherent with the
+/// previous data writes, e.g., after loading or modifying code.
+///
+/// RISC-V only provides `fence.i` for the whole instruction cache of the
+/// current hart. Other harts must execute it themselves.
+#[inline]
+pub fn icache_sync_range(_start: VirtAddr, _size: usize) {
+    unsafe { core::arch::asm!("fence.i") }
+}
+
+/// Cleans and invalidates all data caches of the current hart.
+///
+/// RISC-V has no standard operations on the whole cache, so it only orders
+/// the memory accesses. Platform-specific (e.g., SBI) interfaces should be used
+/// if required.
+#[inline]
+pub fn dcache_clean_invalidate_all() {
+    unsafe { core::arch::asm!("fence rw, rw") }
+}
+
+/// Invalidates all data caches of the current hart.
+///
+/// RISC-V has no standard operations on the whole cache, so it only orders
+/// the memory accesses, same as [`dcache_clean_invalidate_all`].
+///
+/// # Safety
+///
+/// This function is unsafe as all dirty data in the caches may be discarded on
+/// other architectures.
+#[inline]
+pub unsafe fn dcache_invalidate_all() {
+    dcache_clean_invalidate_all()
+}
+
 /// Writes the Supervisor Trap Vector Base Address register (`stvec`).
 ///
 /// # Safety
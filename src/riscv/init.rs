@@ -1,13 +1,74 @@
 //! Helper functions to initialize the CPU states on systems bootstrapping.
 
+use memory_addr::PhysAddr;
+
+use crate::asm::{read_paging_mode, write_satp, PagingMode};
+
+#[cfg(feature = "uspace")]
+pub use super::uaccess::init_uaccess_protection;
+
 /// Initializes trap handling on the current CPU.
 ///
 /// In detail, it initializes the trap vector on RISC-V platforms.
+///
+/// If the `tlb-shootdown` feature is enabled, it also marks the current CPU as
+/// online ([`mark_current_cpu_online`]), so that it receives TLB shootdowns.
+/// The [`CURRENT_CPU_ID`] hook must be registered in that case.
+///
+/// [`mark_current_cpu_online`]: crate::tlb::mark_current_cpu_online
+/// [`CURRENT_CPU_ID`]: crate::tlb::CURRENT_CPU_ID
 pub fn init_trap() {
     unsafe extern "C" {
         fn trap_vector_base();
     }
     unsafe {
-        crate::asm::write_trap_vector_base(trap_vector_base as usize);
+        crate::asm::write_trap_vector_base(trap_vector_base as *const () as usize);
+    }
+    #[cfg(feature = "tlb-shootdown")]
+    crate::tlb::mark_current_cpu_online();
+}
+
+/// Enables the MMU on the current CPU with the given paging mode and page
+/// table root.
+///
+/// Writes to `satp` with an unsupported mode have no effect, so the mode is
+/// read back to check whether it is supported by the current CPU. Returns
+/// `false` if it is not, and `satp` remains unchanged.
+///
+/// The chosen mode is kept in `satp` of each CPU, and is used by later writes
+/// of the page table root, e.g., [`write_user_page_table`].
+///
+/// [`write_user_page_table`]: crate::asm::write_user_page_table
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the address translation configuration.
+/// The page table must be built for `mode`, and map the currently running code.
+pub unsafe fn init_mmu(root_paddr: PhysAddr, mode: PagingMode) -> bool {
+    unsafe { write_satp(mode, root_paddr) };
+    if read_paging_mode() != Some(mode) {
+        return false;
     }
+    crate::asm::flush_tlb(None);
+    true
+}
+
+/// Probes the given paging modes in order, and enables the MMU on the current
+/// CPU with the first one supported.
+///
+/// `root_of(mode)` returns the root of a page table built for `mode`. Returns
+/// the chosen mode, or [`None`] if none of them is supported.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the address translation configuration.
+/// See [`init_mmu`] for details.
+pub unsafe fn probe_and_init_mmu(
+    modes: &[PagingMode],
+    mut root_of: impl FnMut(PagingMode) -> PhysAddr,
+) -> Option<PagingMode> {
+    modes
+        .iter()
+        .copied()
+        .find(|&mode| unsafe { init_mmu(root_of(mode), mode) })
 }
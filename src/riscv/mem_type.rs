@@ -0,0 +1,24 @@
+use crate::mem_type::MemoryType;
+
+impl MemoryType {
+    /// Returns the Svpbmt attribute bits (`PBMT`, bits 62:61) of page table
+    /// entries.
+    ///
+    /// [`MemoryType::WriteBack`] uses the physical memory attributes (PMA) of
+    /// the platform, write-through and write-combining memory fall back to
+    /// non-cacheable (`NC`), and device memory is `IO`.
+    ///
+    /// The bits must be zero if the Svpbmt extension is not supported, where
+    /// only the PMA takes effect.
+    pub const fn pte_bits(self) -> u64 {
+        const PMA: u64 = 0;
+        const NC: u64 = 1;
+        const IO: u64 = 2;
+        let pbmt = match self {
+            Self::WriteBack => PMA,
+            Self::WriteThrough | Self::WriteCombining | Self::Uncached => NC,
+            Self::Device | Self::StronglyOrdered => IO,
+        };
+        pbmt << 61
+    }
+}
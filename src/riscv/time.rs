@@ -0,0 +1,15 @@
+//! Cycle counter based on the `time` CSR.
+//!
+//! The `time` CSR counts at a constant frequency on all harts. The frequency is
+//! not discoverable from S-mode.
+
+/// Returns the current value of the cycle counter (`rdtime`).
+#[inline]
+pub fn cycles() -> u64 {
+    riscv::register::time::read64()
+}
+
+/// The frequency of the `time` CSR is not discoverable from S-mode.
+pub(crate) fn detect_cycle_frequency() -> Option<u64> {
+    None
+}
@@ -0,0 +1,47 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use riscv::register::sstatus;
+
+use crate::uaccess::UserAccessGuard;
+
+static PROTECTED: AtomicBool = AtomicBool::new(false);
+
+/// Enables the protection of user memory on the current CPU.
+///
+/// It clears `sstatus.SUM`, and contexts created by [`UspaceContext::new`]
+/// afterwards no longer set it, so the kernel cannot access user memory
+/// after traps from user space, except in the scope of a [`UserAccessGuard`].
+///
+/// It is opt-in, and should be called after [`init_trap`] on each CPU, before
+/// any user context is created.
+///
+/// [`UspaceContext::new`]: crate::uspace::UspaceContext::new
+/// [`init_trap`]: crate::init::init_trap
+pub fn init_uaccess_protection() {
+    PROTECTED.store(true, Ordering::Release);
+    unsafe { sstatus::clear_sum() }
+}
+
+/// Returns whether the protection of user memory is enabled.
+#[inline]
+pub(super) fn is_protected() -> bool {
+    PROTECTED.load(Ordering::Acquire)
+}
+
+impl UserAccessGuard {
+    /// Sets `sstatus.SUM`. Returns whether it was clear before.
+    #[inline]
+    pub(crate) fn allow_user_access() -> bool {
+        let forbidden = !sstatus::read().sum();
+        if forbidden {
+            unsafe { sstatus::set_sum() }
+        }
+        forbidden
+    }
+
+    /// Clears `sstatus.SUM`.
+    #[inline]
+    pub(crate) fn forbid_user_access() {
+        unsafe { sstatus::clear_sum() }
+    }
+}
@@ -21,7 +21,8 @@ impl UspaceContext {
     pub fn new(entry: usize, ustack_top: VirtAddr, arg0: usize) -> Self {
         let mut sstatus = Sstatus::from_bits(0);
         sstatus.set_spie(true); // enable interrupts
-        sstatus.set_sum(true); // enable user memory access in supervisor mode
+                                // enable user memory access in supervisor mode, unless protected
+        sstatus.set_sum(!super::uaccess::is_protected());
         #[cfg(feature = "fp-simd")]
         {
             sstatus.set_fs(FS::Initial); // set the FPU to initial state
@@ -0,0 +1,90 @@
+//! Task lifecycle hooks.
+
+use crate::asm::{disable_irqs, enable_irqs, irqs_enabled};
+use crate::TaskContext;
+
+pub use linkme::distributed_slice as def_task_hook;
+pub use linkme::distributed_slice as register_task_hook;
+
+/// A slice of functions called when the entry function of a task returns.
+///
+/// It only applies to tasks initialized by `TaskContext::init_with_arg`. The
+/// function should exit the current task and never return.
+#[def_task_hook]
+pub static TASK_EXIT: [fn() -> !];
+
+/// A slice of functions called on the next task's stack to finish a context
+/// switch, e.g., to release the run queue lock held by the previous task.
+///
+/// It is called after each switch by [`TaskContext::switch_to_irqsave`], no
+/// matter how the next task was switched out, including before the entry
+/// function of tasks initialized by `TaskContext::init_with_arg`. IRQs are
+/// always disabled when it is called.
+#[def_task_hook]
+pub static FINISH_SWITCH: [fn()];
+
+impl TaskContext {
+    /// Switches to another task like [`TaskContext::switch_to`], with the IRQ
+    /// state handed over.
+    ///
+    /// IRQs are disabled before switching, and the next task calls the hook
+    /// registered to [`FINISH_SWITCH`] after switching, whether it was switched
+    /// out by this function or by [`TaskContext::switch_to`]. Then it restores
+    /// IRQs to the state before it was switched out. Newly created tasks (by
+    /// `TaskContext::init_with_arg`) start with IRQs enabled after the hook.
+    ///
+    /// When the current task is switched back, IRQs are restored to the state
+    /// before this function was called, and the hook is called if it is
+    /// switched back by this function.
+    pub fn switch_to_irqsave(&mut self, next_ctx: &Self) {
+        let irqs_were_enabled = irqs_enabled();
+        disable_irqs();
+        if self.switch_to_impl(next_ctx, true) {
+            finish_switch(irqs_were_enabled);
+        } else if irqs_were_enabled {
+            enable_irqs();
+        }
+    }
+}
+
+/// Called on the next task's stack after a switch by
+/// [`TaskContext::switch_to_irqsave`], where `irqs_were_enabled` is the IRQ
+/// state of the next task before it was switched out.
+pub(crate) fn finish_switch(irqs_were_enabled: bool) {
+    call_finish_switch_hook();
+    if irqs_were_enabled {
+        enable_irqs();
+    }
+}
+
+fn call_finish_switch_hook() {
+    let mut iter = FINISH_SWITCH.iter();
+    if let Some(func) = iter.next() {
+        if iter.next().is_some() {
+            warn!("Multiple handlers for finish switch are not currently supported");
+        }
+        func()
+    }
+}
+
+/// Called by the task entry trampoline before the entry function of a task,
+/// where `irqsave` tells whether the task is entered by
+/// [`TaskContext::switch_to_irqsave`].
+pub(crate) extern "C" fn task_entry_finish_switch(irqsave: bool) {
+    if irqsave {
+        finish_switch(true);
+    }
+}
+
+/// Called by the task entry trampoline after the entry function of a task returns.
+pub(crate) extern "C" fn task_exit() -> ! {
+    let mut iter = TASK_EXIT.iter();
+    if let Some(func) = iter.next() {
+        if iter.next().is_some() {
+            warn!("Multiple handlers for task exit are not currently supported");
+        }
+        func()
+    } else {
+        panic!("Task entry returned, but no task exit hook is registered");
+    }
+}
@@ -0,0 +1,139 @@
+//! Cycle counter and its frequency.
+//!
+//! [`cycles`] reads a monotonic counter that runs at a constant frequency on
+//! all CPUs: the invariant TSC on x86_64, `CNTVCT_EL0` on AArch64, the `time`
+//! CSR on RISC-V, and the stable counter on LoongArch64.
+//!
+//! The frequency is determined once by [`init_cycle_frequency`] on the boot
+//! CPU, or set by [`set_cycle_frequency`], e.g., from the firmware. Before
+//! that, [`cycle_frequency`] returns zero.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        pub use crate::x86_64::time::*;
+        use crate::x86_64::time as arch;
+    } else if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
+        pub use crate::riscv::time::*;
+        use crate::riscv::time as arch;
+    } else if #[cfg(target_arch = "aarch64")] {
+        pub use crate::aarch64::time::*;
+        use crate::aarch64::time as arch;
+    } else if #[cfg(target_arch = "loongarch64")] {
+        pub use crate::loongarch64::time::*;
+        use crate::loongarch64::time as arch;
+    }
+}
+
+/// The frequency of the cycle counter in Hz, or zero if not determined yet.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Determines the frequency of the cycle counter, unless it has been set by
+/// [`set_cycle_frequency`].
+///
+/// The source of the frequency is architecture-specific:
+///
+/// - x86_64: the TSC and core crystal clock information (`CPUID.15H`),
+///   calibration against the PIT channel 2 (on bare metal only), or the
+///   processor base frequency (`CPUID.16H`), in order;
+/// - AArch64: `CNTFRQ_EL0`;
+/// - RISC-V: none, as it is not discoverable from S-mode, so it must be set by
+///   [`set_cycle_frequency`] (e.g., from the `timebase-frequency` property in
+///   the device tree);
+/// - LoongArch64: the crystal frequency (`CPUCFG` word 4) multiplied by the
+///   ratio in `CPUCFG` word 5.
+///
+/// It should be called once on the boot CPU, since the calibration may take
+/// several milliseconds. Returns the frequency in Hz, or zero if it cannot be
+/// determined.
+pub fn init_cycle_frequency() -> u64 {
+    let freq = FREQUENCY.load(Ordering::Acquire);
+    if freq != 0 {
+        return freq;
+    }
+    let freq = arch::detect_cycle_frequency().unwrap_or(0);
+    FREQUENCY.store(freq, Ordering::Release);
+    freq
+}
+
+/// Returns the frequency of the cycle counter in Hz, or zero if it has not
+/// been determined by [`init_cycle_frequency`] or [`set_cycle_frequency`].
+#[inline]
+pub fn cycle_frequency() -> u64 {
+    FREQUENCY.load(Ordering::Acquire)
+}
+
+/// Sets the frequency of the cycle counter in Hz, e.g., obtained from the
+/// firmware.
+pub fn set_cycle_frequency(freq: u64) {
+    FREQUENCY.store(freq, Ordering::Release);
+}
+
+/// Converts cycles of the cycle counter to nanoseconds.
+///
+/// Returns zero if the cycle frequency is unknown, and saturates at
+/// [`u64::MAX`] if the result overflows.
+#[inline]
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    cycles_to_nanos_at(cycles, cycle_frequency())
+}
+
+const fn cycles_to_nanos_at(cycles: u64, freq: u64) -> u64 {
+    if freq == 0 {
+        return 0;
+    }
+    let nanos = cycles as u128 * 1_000_000_000 / freq as u128;
+    if nanos > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        nanos as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_frequency() {
+        assert_eq!(cycles_to_nanos_at(12345, 0), 0);
+        assert_eq!(cycles_to_nanos_at(u64::MAX, 0), 0);
+    }
+
+    #[test]
+    fn conversion() {
+        assert_eq!(cycles_to_nanos_at(0, 1_000_000_000), 0);
+        assert_eq!(
+            cycles_to_nanos_at(3_000_000_000, 3_000_000_000),
+            1_000_000_000
+        );
+        assert_eq!(cycles_to_nanos_at(24, 24_000_000), 1_000);
+        // rounds down
+        assert_eq!(cycles_to_nanos_at(1, 3_000_000_000), 0);
+        assert_eq!(cycles_to_nanos_at(5, 3_000_000_000), 1);
+    }
+
+    #[test]
+    fn no_intermediate_overflow() {
+        // `cycles * 10^9` overflows `u64`, but the result does not.
+        assert_eq!(cycles_to_nanos_at(u64::MAX, 1_000_000_000), u64::MAX);
+        assert_eq!(
+            cycles_to_nanos_at(u64::MAX / 2, 2_000_000_000),
+            u64::MAX / 4
+        );
+    }
+
+    #[test]
+    fn saturates_on_overflow() {
+        assert_eq!(cycles_to_nanos_at(u64::MAX, 1), u64::MAX);
+        assert_eq!(cycles_to_nanos_at(u64::MAX / 1000 + 1, 1_000_000), u64::MAX);
+    }
+
+    #[test]
+    fn set_frequency() {
+        set_cycle_frequency(2_000_000_000);
+        assert_eq!(cycle_frequency(), 2_000_000_000);
+        assert_eq!(cycles_to_nanos(4_000_000_000), 2_000_000_000);
+    }
+}
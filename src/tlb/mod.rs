@@ -0,0 +1,90 @@
+//! Common policies of TLB maintenance.
+//!
+//! The TLB maintenance operations themselves are architecture-specific, see
+//! `flush_tlb`, `flush_tlb_range` and `flush_tlb_asid` in [`asm`](crate::asm).
+//! With the `tlb-shootdown` feature, [`shootdown`] can be used to flush the
+//! TLBs of other CPUs as well.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "tlb-shootdown")]
+mod shootdown;
+
+#[cfg(feature = "tlb-shootdown")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tlb-shootdown")))]
+pub use self::shootdown::{
+    def_tlb_hook, handle_shootdown_ipi, mark_current_cpu_online, register_tlb_hook, shootdown,
+    CpuMask, TlbFlush, CURRENT_CPU_ID, MAX_CPUS, SEND_SHOOTDOWN_IPI,
+};
+
+#[cfg(all(feature = "tlb-shootdown", feature = "uspace"))]
+pub(crate) use self::shootdown::set_active_root;
+
+/// The default value of [`flush_range_threshold`].
+pub const DEFAULT_FLUSH_RANGE_THRESHOLD: usize = 64;
+
+/// Whether the range and ASID TLB invalidations (`flush_tlb_range` and
+/// `flush_tlb_asid`) are broadcast to all CPUs by hardware.
+///
+/// It is `true` on AArch64, where the inner shareable `TLBI` operations are
+/// used. On other architectures, they only affect the current CPU, and other
+/// CPUs must be notified to flush their own TLBs.
+pub const HW_BROADCAST: bool = cfg!(target_arch = "aarch64");
+
+static FLUSH_RANGE_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_FLUSH_RANGE_THRESHOLD);
+
+/// Returns the maximum number of 4K pages flushed one by one by
+/// `flush_tlb_range`.
+///
+/// Larger ranges fall back to flushing the whole address space, which is
+/// cheaper than invalidating so many pages.
+pub fn flush_range_threshold() -> usize {
+    FLUSH_RANGE_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Sets the maximum number of 4K pages flushed one by one by
+/// `flush_tlb_range`. See [`flush_range_threshold`].
+pub fn set_flush_range_threshold(pages: usize) {
+    FLUSH_RANGE_THRESHOLD.store(pages, Ordering::Relaxed);
+}
+
+/// Returns the range of 4K page numbers covering `[start, start + size)`,
+/// which is empty if `size` is zero.
+pub(crate) fn page_range(start: usize, size: usize) -> core::ops::Range<usize> {
+    let first = start >> 12;
+    if size == 0 {
+        return first..first;
+    }
+    first..start.saturating_add(size).div_ceil(4096)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::page_range;
+
+    #[test]
+    fn page_range_aligned() {
+        assert_eq!(page_range(0x1000, 0x3000), 1..4);
+        assert_eq!(page_range(0, 0x1000), 0..1);
+    }
+
+    #[test]
+    fn page_range_unaligned() {
+        assert_eq!(page_range(0x1fff, 2), 1..3);
+        assert_eq!(page_range(0x1800, 0x1000), 1..3);
+        assert_eq!(page_range(0x1234, 1), 1..2);
+    }
+
+    #[test]
+    fn page_range_empty() {
+        assert!(page_range(0x1000, 0).is_empty());
+        assert!(page_range(0x1800, 0).is_empty());
+    }
+
+    #[test]
+    fn page_range_saturates() {
+        let range = page_range(usize::MAX - 0xfff, 0x2000);
+        assert_eq!(range, (usize::MAX >> 12)..usize::MAX.div_ceil(4096));
+        assert_eq!(range.len(), 1);
+    }
+}
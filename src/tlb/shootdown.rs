@@ -0,0 +1,292 @@
+//! Cross-CPU TLB shootdown.
+
+use core::cell::UnsafeCell;
+#[cfg(not(target_arch = "aarch64"))]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use memory_addr::{PhysAddr, VirtAddr};
+
+pub use linkme::distributed_slice as def_tlb_hook;
+pub use linkme::distributed_slice as register_tlb_hook;
+
+/// The maximum number of CPUs supported by the TLB shootdown.
+pub const MAX_CPUS: usize = 64;
+
+/// A slice of functions returning the ID of the current CPU.
+///
+/// The ID must be less than [`MAX_CPUS`].
+#[def_tlb_hook]
+pub static CURRENT_CPU_ID: [fn() -> usize];
+
+/// A slice of functions sending the TLB shootdown IPI to the given CPUs.
+///
+/// The receivers should call [`handle_shootdown_ipi`] in their IRQ handlers.
+/// On RISC-V, if no function is registered, the SBI remote fences (RFENCE
+/// extension) are used instead, where CPU IDs are regarded as hart IDs.
+#[def_tlb_hook]
+pub static SEND_SHOOTDOWN_IPI: [fn(CpuMask)];
+
+/// A set of CPUs represented as a bitmask.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuMask(u64);
+
+impl CpuMask {
+    /// Creates an empty set.
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    /// Creates a set from the bitmask, where bit `i` stands for CPU `i`.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the bitmask of the set.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns whether the set contains the given CPU.
+    pub const fn contains(self, cpu_id: usize) -> bool {
+        cpu_id < MAX_CPUS && self.0 & (1 << cpu_id) != 0
+    }
+
+    /// Returns whether the set is empty.
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns an iterator over the IDs of the CPUs in the set.
+    pub fn iter(self) -> impl Iterator<Item = usize> {
+        (0..MAX_CPUS).filter(move |&i| self.contains(i))
+    }
+}
+
+/// A TLB flush operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlbFlush {
+    /// Flushes the entire TLB.
+    All,
+    /// Flushes all non-global entries of the given ASID.
+    Asid(usize),
+    /// Flushes the entries that map the given virtual address range, see
+    /// `flush_tlb_range` in [`asm`](crate::asm).
+    Range {
+        /// The start address of the range.
+        start: VirtAddr,
+        /// The size of the range.
+        size: usize,
+        /// The ASID of the entries, or [`None`] for all address spaces.
+        asid: Option<usize>,
+    },
+}
+
+impl TlbFlush {
+    /// Performs the flush on the current CPU.
+    pub fn flush_local(self) {
+        match self {
+            Self::All => crate::asm::flush_tlb(None),
+            Self::Asid(asid) => crate::asm::flush_tlb_asid(asid),
+            Self::Range { start, size, asid } => crate::asm::flush_tlb_range(start, size, asid),
+        }
+    }
+}
+
+/// The pending flush request, protected by `REQUEST_LOCK`.
+struct Request(UnsafeCell<TlbFlush>);
+
+unsafe impl Sync for Request {}
+
+static ONLINE_CPUS: AtomicU64 = AtomicU64::new(0);
+#[cfg_attr(target_arch = "aarch64", allow(dead_code))]
+static ACTIVE_ROOTS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+#[cfg(not(target_arch = "aarch64"))]
+static REQUEST_LOCK: AtomicBool = AtomicBool::new(false);
+static REQUEST: Request = Request(UnsafeCell::new(TlbFlush::All));
+static PENDING_CPUS: AtomicU64 = AtomicU64::new(0);
+
+fn registered_cpu_id() -> Option<usize> {
+    CURRENT_CPU_ID.first().map(|current_cpu_id| {
+        let cpu_id = current_cpu_id();
+        assert!(cpu_id < MAX_CPUS, "CPU ID {cpu_id} exceeds MAX_CPUS");
+        cpu_id
+    })
+}
+
+fn current_cpu_id() -> usize {
+    registered_cpu_id().expect("No registered hook for the current CPU ID")
+}
+
+/// Marks the current CPU as online, so that it receives TLB shootdowns.
+///
+/// It is called by `init_trap` in [`init`](crate::init).
+pub fn mark_current_cpu_online() {
+    ONLINE_CPUS.fetch_or(1 << current_cpu_id(), Ordering::SeqCst);
+}
+
+/// Records the user page table root which is going to be active on the
+/// current CPU.
+///
+/// It is called by `TaskContext` on each switch, including the first load of a
+/// task on a CPU, whether or not the page table root register is changed.
+#[cfg(feature = "uspace")]
+pub(crate) fn set_active_root(root: PhysAddr) {
+    if let Some(cpu_id) = registered_cpu_id() {
+        ACTIVE_ROOTS[cpu_id].store(root.as_usize(), Ordering::SeqCst);
+    }
+}
+
+/// Performs the TLB flush on all CPUs that may cache the affected entries, and
+/// waits until they have finished.
+///
+/// If `root` is given, the flush only applies to the address space with that
+/// page table root, so only the CPUs which have it active are notified. CPUs
+/// whose active root is unknown are notified as well. Otherwise, all online
+/// CPUs are notified, e.g., for changes of kernel mappings.
+///
+/// On AArch64, the TLB maintenance operations are broadcast by hardware, so no
+/// IPI is sent.
+///
+/// # Deadlocks
+///
+/// It spins until all notified CPUs have handled the IPI, with no timeout. So
+/// the notified CPUs must take the IPI eventually, i.e., they must not keep
+/// IRQs disabled indefinitely, e.g., while spinning on a lock held by the
+/// caller. The caller may have IRQs disabled, as it handles the requests of
+/// other CPUs while waiting to send its own.
+pub fn shootdown(flush: TlbFlush, root: Option<PhysAddr>) {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let _ = root;
+        match flush {
+            TlbFlush::All => crate::asm::flush_tlb_all_cpus(),
+            _ => flush.flush_local(),
+        }
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        flush.flush_local();
+        let mut targets = ONLINE_CPUS.load(Ordering::SeqCst) & !(1 << current_cpu_id());
+        if let Some(root) = root {
+            for (cpu_id, active_root) in ACTIVE_ROOTS.iter().enumerate() {
+                let active_root = active_root.load(Ordering::SeqCst);
+                if active_root != 0 && active_root != root.as_usize() {
+                    targets &= !(1 << cpu_id);
+                }
+            }
+        }
+        if targets != 0 {
+            send_request(flush, CpuMask(targets));
+        }
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn send_request(flush: TlbFlush, targets: CpuMask) {
+    let Some(send_ipi) = SEND_SHOOTDOWN_IPI.first() else {
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        return sbi_remote_fence(flush, targets);
+        #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+        panic!("No registered hook for sending TLB shootdown IPIs");
+    };
+
+    // Handle requests from other CPUs while waiting, to avoid deadlocks.
+    while REQUEST_LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        handle_shootdown_ipi();
+        core::hint::spin_loop();
+    }
+    unsafe { *REQUEST.0.get() = flush };
+    PENDING_CPUS.store(targets.bits(), Ordering::Release);
+    send_ipi(targets);
+    while PENDING_CPUS.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+    REQUEST_LOCK.store(false, Ordering::Release);
+}
+
+/// Handles the TLB shootdown IPI on the current CPU.
+///
+/// It performs the pending flush request for the current CPU if any, and
+/// should be called in the IRQ handler of the IPI sent by
+/// [`SEND_SHOOTDOWN_IPI`].
+pub fn handle_shootdown_ipi() {
+    let bit = 1 << current_cpu_id();
+    if PENDING_CPUS.load(Ordering::Acquire) & bit != 0 {
+        let flush = unsafe { *REQUEST.0.get() };
+        flush.flush_local();
+        PENDING_CPUS.fetch_and(!bit, Ordering::Release);
+    }
+}
+
+/// Flushes the TLBs of the given harts through the SBI RFENCE extension.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+fn sbi_remote_fence(flush: TlbFlush, targets: CpuMask) {
+    const EID_RFNC: usize = 0x5246_4E43;
+    const FID_REMOTE_SFENCE_VMA: usize = 1;
+    const FID_REMOTE_SFENCE_VMA_ASID: usize = 2;
+
+    // A size of `usize::MAX` means flushing the entire address space.
+    let (fid, start, size, asid) = match flush {
+        TlbFlush::All => (FID_REMOTE_SFENCE_VMA, 0, usize::MAX, 0),
+        TlbFlush::Asid(asid) => (FID_REMOTE_SFENCE_VMA_ASID, 0, usize::MAX, asid),
+        TlbFlush::Range { start, size, asid } => match asid {
+            Some(asid) => (FID_REMOTE_SFENCE_VMA_ASID, start.as_usize(), size, asid),
+            None => (FID_REMOTE_SFENCE_VMA, start.as_usize(), size, 0),
+        },
+    };
+    let error: isize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") targets.bits() as usize => error,
+            inlateout("a1") 0usize => _,
+            in("a2") start,
+            in("a3") size,
+            in("a4") asid,
+            in("a6") fid,
+            in("a7") EID_RFNC,
+        )
+    }
+    if error != 0 {
+        warn!("SBI remote fence failed: {error}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CpuMask, MAX_CPUS};
+
+    #[test]
+    fn cpu_mask_empty() {
+        let mask = CpuMask::new();
+        assert!(mask.is_empty());
+        assert_eq!(mask, CpuMask::default());
+        assert_eq!(mask.iter().count(), 0);
+        assert!(!mask.contains(0));
+    }
+
+    #[test]
+    fn cpu_mask_bits() {
+        let mask = CpuMask::from_bits(0x8000_0000_0000_0005);
+        assert_eq!(mask.bits(), 0x8000_0000_0000_0005);
+        assert!(!mask.is_empty());
+        assert!(mask.contains(0));
+        assert!(!mask.contains(1));
+        assert!(mask.contains(2));
+        assert!(mask.contains(MAX_CPUS - 1));
+        assert!(mask.iter().eq([0, 2, MAX_CPUS - 1]));
+    }
+
+    #[test]
+    fn cpu_mask_out_of_range() {
+        let mask = CpuMask::from_bits(u64::MAX);
+        assert!(!mask.contains(MAX_CPUS));
+        assert!(!mask.contains(usize::MAX));
+        assert_eq!(mask.iter().count(), MAX_CPUS);
+    }
+}
@@ -1,5 +1,8 @@
 //! Trap handling.
 
+#[cfg(target_arch = "x86_64")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use memory_addr::VirtAddr;
 
 pub use crate::TrapFrame;
@@ -15,6 +18,43 @@ pub static IRQ: [fn(usize) -> bool];
 #[def_trap_handler]
 pub static PAGE_FAULT: [fn(VirtAddr, PageFaultFlags, bool) -> bool];
 
+/// A slice of exception handler functions on x86_64.
+///
+/// They are called with the trap frame, the exception vector and the error
+/// code (zero if not pushed by the CPU), for all exceptions except page faults
+/// (see [`PAGE_FAULT`]), NMIs (see [`NMI`]), `#DF` and `#MC`. Returns whether
+/// the exception is handled. Unhandled breakpoints (`#BP`) are logged and
+/// skipped, and other unhandled exceptions cause a panic.
+#[cfg(target_arch = "x86_64")]
+#[def_trap_handler]
+pub static EXCEPTION: [fn(&mut TrapFrame, u8, u64) -> bool];
+
+/// A slice of NMI handler functions on x86_64.
+///
+/// Since an NMI may have multiple sources (e.g., watchdogs, IPIs sent by
+/// `apic::send_nmi` and legacy platform errors), all of them are called with
+/// the trap frame, and each returns whether the NMI is caused by its source.
+/// They run with NMIs blocked and may interrupt any code, including the logger
+/// and lock holders, so they must not log or take locks.
+///
+/// If no handler claims the NMI, legacy platform errors reported by the
+/// system control port B cause a panic, and other NMIs are counted in
+/// [`unknown_nmi_count`].
+#[cfg(target_arch = "x86_64")]
+#[def_trap_handler]
+pub static NMI: [fn(&TrapFrame) -> bool];
+
+/// The number of NMIs not claimed by any handler in [`NMI`].
+#[cfg(target_arch = "x86_64")]
+pub(crate) static UNKNOWN_NMI_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of NMIs not claimed by any handler in [`NMI`] nor caused
+/// by legacy platform errors, on all CPUs, on x86_64.
+#[cfg(target_arch = "x86_64")]
+pub fn unknown_nmi_count() -> usize {
+    UNKNOWN_NMI_COUNT.load(Ordering::Relaxed)
+}
+
 /// A slice of syscall handler functions.
 #[cfg(feature = "uspace")]
 #[cfg_attr(docsrs, doc(cfg(feature = "uspace")))]
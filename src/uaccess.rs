@@ -0,0 +1,52 @@
+//! Protection of user memory from kernel accesses.
+//!
+//! After [`init_uaccess_protection`] is called, the kernel can access user
+//! memory only in the scope of a [`UserAccessGuard`]. The protection is
+//! provided by SMAP on x86_64, Privileged Access Never (PAN) on AArch64, and
+//! `sstatus.SUM` on RISC-V. LoongArch has no such mechanism, so user memory is
+//! always accessible there.
+
+use core::marker::PhantomData;
+
+pub use crate::init::init_uaccess_protection;
+
+/// A guard that allows the kernel to access user memory in its scope.
+///
+/// It lifts the protection on creation, and restores it on drop unless it was
+/// already lifted, so guards can be nested. It does nothing if the protection
+/// is not enabled or not supported.
+///
+/// The guard should not be held across context switches, since the state of
+/// the protection (e.g., `RFLAGS.AC` on x86_64) is not saved in the
+/// [`TaskContext`](crate::TaskContext).
+#[must_use]
+pub struct UserAccessGuard {
+    forbid_on_drop: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl UserAccessGuard {
+    /// Allows the kernel to access user memory until the guard is dropped.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            forbid_on_drop: Self::allow_user_access(),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for UserAccessGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for UserAccessGuard {
+    #[inline]
+    fn drop(&mut self) {
+        if self.forbid_on_drop {
+            Self::forbid_user_access();
+        }
+    }
+}
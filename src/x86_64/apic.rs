@@ -0,0 +1,322 @@
+//! Local APIC driver, supporting both xAPIC (MMIO) and x2APIC (MSR) modes.
+//!
+//! The x2APIC mode is enabled by [`init_local_apic`] if it is supported by the
+//! CPU (`CPUID.01H:ECX[21]`). Before the local APIC of a CPU is initialized,
+//! the mode set by the firmware (`IA32_APIC_BASE.EXTD`) is used. In xAPIC
+//! mode, the registers are accessed through the MMIO region set by
+//! [`set_xapic_mmio_base`], and the functions accessing them panic if it is
+//! not set.
+//!
+//! The local APIC is initialized by [`init_local_apic`], which is called by
+//! [`init_trap`](crate::init::init_trap) if the `apic` feature is enabled.
+//! Handlers registered to [`IRQ`](crate::trap::IRQ) should call [`eoi`] to
+//! acknowledge the interrupts from the local APIC.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use memory_addr::VirtAddr;
+use x86::msr::{self, IA32_APIC_BASE, IA32_TSC_DEADLINE};
+
+use super::features::cpu_features;
+
+/// The vector of spurious interrupts, which are ignored without EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Local APIC registers, as the MMIO offset divided by 16 in xAPIC mode, or the
+/// MSR index minus `0x800` in x2APIC mode.
+mod reg {
+    pub const ID: u32 = 0x02;
+    pub const VERSION: u32 = 0x03;
+    pub const TPR: u32 = 0x08;
+    pub const EOI: u32 = 0x0b;
+    pub const SVR: u32 = 0x0f;
+    pub const ESR: u32 = 0x28;
+    pub const ICR_LOW: u32 = 0x30;
+    pub const ICR_HIGH: u32 = 0x31;
+    pub const LVT_TIMER: u32 = 0x32;
+    pub const TIMER_INIT_COUNT: u32 = 0x38;
+    pub const TIMER_CURRENT_COUNT: u32 = 0x39;
+    pub const TIMER_DIVIDE_CONFIG: u32 = 0x3e;
+}
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+/// Divide the bus clock by 1 (`0b1011`).
+const TIMER_DIVIDE_BY_1: u32 = 0b1011;
+
+static XAPIC_MMIO_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// The mode of the local APIC on the current CPU, or [`MODE_UNKNOWN`] if it
+/// is not initialized by [`init_local_apic`] nor read from `IA32_APIC_BASE`.
+#[percpu::def_percpu]
+static APIC_MODE: u8 = MODE_UNKNOWN;
+
+const MODE_UNKNOWN: u8 = 0;
+const MODE_XAPIC: u8 = 1;
+const MODE_X2APIC: u8 = 2;
+
+/// Modes of the local APIC timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Fires once after the initial count reaches zero.
+    OneShot = 0b00,
+    /// Fires periodically, reloading the initial count each time.
+    Periodic = 0b01,
+    /// Fires once the TSC reaches the deadline written by
+    /// [`set_tsc_deadline`].
+    TscDeadline = 0b10,
+}
+
+/// Destinations of inter-processor interrupts (IPIs).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiDest {
+    /// The CPU with the given local APIC ID.
+    Apic(u32),
+    /// The current CPU.
+    SelfOnly,
+    /// All CPUs, including the current one.
+    AllIncludingSelf,
+    /// All CPUs except the current one.
+    AllExcludingSelf,
+}
+
+impl IpiDest {
+    const fn shorthand(self) -> u32 {
+        match self {
+            Self::Apic(_) => 0b00,
+            Self::SelfOnly => 0b01,
+            Self::AllIncludingSelf => 0b10,
+            Self::AllExcludingSelf => 0b11,
+        }
+    }
+}
+
+/// Delivery modes of IPIs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeliveryMode {
+    Fixed = 0b000,
+    Nmi = 0b100,
+    Init = 0b101,
+    StartUp = 0b110,
+}
+
+/// Returns whether the x2APIC mode is enabled on the current CPU.
+///
+/// Before [`init_local_apic`], the mode is read from `IA32_APIC_BASE.EXTD`
+/// once, since the firmware may have enabled the x2APIC mode.
+#[inline]
+pub fn is_x2apic() -> bool {
+    match unsafe { APIC_MODE.read_current_raw() } {
+        MODE_UNKNOWN => {
+            let x2apic = unsafe { msr::rdmsr(IA32_APIC_BASE) } & APIC_BASE_X2APIC != 0;
+            let mode = if x2apic { MODE_X2APIC } else { MODE_XAPIC };
+            unsafe { APIC_MODE.write_current_raw(mode) };
+            x2apic
+        }
+        mode => mode == MODE_X2APIC,
+    }
+}
+
+/// Returns whether the TSC-deadline timer mode is supported.
+#[inline]
+pub fn has_tsc_deadline() -> bool {
+    cpu_features().tsc_deadline
+}
+
+/// Sets the virtual address of the xAPIC MMIO region, whose physical address
+/// is in the `IA32_APIC_BASE` MSR (`0xfee0_0000` by default).
+///
+/// It is only required if x2APIC is not supported, or the local APIC is
+/// accessed before [`init_local_apic`]. It must be called before that.
+///
+/// # Safety
+///
+/// The address must map the xAPIC MMIO region as uncached device memory, and
+/// must remain valid while the local APIC is in use.
+pub unsafe fn set_xapic_mmio_base(vaddr: VirtAddr) {
+    XAPIC_MMIO_BASE.store(vaddr.as_usize(), Ordering::Release);
+}
+
+/// Returns the address of the given register in the xAPIC MMIO region.
+///
+/// # Panics
+///
+/// Panics if the MMIO region is not set by [`set_xapic_mmio_base`].
+fn xapic_reg(reg: u32) -> *mut u32 {
+    let base = XAPIC_MMIO_BASE.load(Ordering::Acquire);
+    assert!(base != 0, "xAPIC MMIO base is not set");
+    (base + (reg as usize) * 16) as *mut u32
+}
+
+fn read(reg: u32) -> u32 {
+    if is_x2apic() {
+        unsafe { msr::rdmsr(0x800 + reg) as u32 }
+    } else {
+        unsafe { xapic_reg(reg).read_volatile() }
+    }
+}
+
+fn write(reg: u32, value: u32) {
+    if is_x2apic() {
+        unsafe { msr::wrmsr(0x800 + reg, value as u64) }
+    } else {
+        unsafe { xapic_reg(reg).write_volatile(value) }
+    }
+}
+
+/// Initializes the local APIC on the current CPU.
+///
+/// It enables the local APIC (in x2APIC mode if supported), sets the
+/// spurious interrupt vector to [`SPURIOUS_VECTOR`], accepts all interrupt
+/// priorities, and masks the timer.
+///
+/// In xAPIC mode, it does nothing but logs a warning if the MMIO region is not
+/// set by [`set_xapic_mmio_base`].
+pub fn init_local_apic() {
+    let x2apic = cpu_features().x2apic;
+    if !x2apic && XAPIC_MMIO_BASE.load(Ordering::Acquire) == 0 {
+        warn!("xAPIC MMIO base is not set, skip initializing local APIC");
+        return;
+    }
+    unsafe {
+        let mut base = msr::rdmsr(IA32_APIC_BASE) | APIC_BASE_ENABLE;
+        if x2apic {
+            base |= APIC_BASE_X2APIC;
+        }
+        msr::wrmsr(IA32_APIC_BASE, base);
+        APIC_MODE.write_current_raw(if x2apic { MODE_X2APIC } else { MODE_XAPIC });
+    }
+    write(reg::TPR, 0);
+    write(reg::LVT_TIMER, LVT_MASKED);
+    write(reg::ESR, 0);
+    write(reg::SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+/// Returns the local APIC ID of the current CPU.
+pub fn local_apic_id() -> u32 {
+    if is_x2apic() {
+        read(reg::ID)
+    } else {
+        read(reg::ID) >> 24
+    }
+}
+
+/// Returns the value of the local APIC version register.
+pub fn version() -> u32 {
+    read(reg::VERSION)
+}
+
+/// Signals the end of the current interrupt (EOI).
+#[inline]
+pub fn eoi() {
+    write(reg::EOI, 0);
+}
+
+/// Configures the local APIC timer with the given interrupt vector and mode.
+///
+/// The timer counts at the bus clock frequency (divided by 1) in one-shot and
+/// periodic modes. It does not fire until [`set_timer_initial_count`] or
+/// [`set_tsc_deadline`] is called.
+///
+/// # Panics
+///
+/// Panics if the TSC-deadline mode is requested but not supported.
+pub fn init_timer(vector: u8, mode: TimerMode) {
+    if mode == TimerMode::TscDeadline {
+        assert!(has_tsc_deadline(), "TSC-deadline timer is not supported");
+    } else {
+        write(reg::TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_1);
+    }
+    write(reg::LVT_TIMER, (mode as u32) << 17 | vector as u32);
+    if mode == TimerMode::TscDeadline {
+        // Order the LVT write before later writes to `IA32_TSC_DEADLINE`, which
+        // are ignored before the TSC-deadline mode is set (SDM Vol. 3A,
+        // 10.5.4.1).
+        unsafe { core::arch::asm!("mfence; lfence") }
+    }
+}
+
+/// Starts the timer in one-shot or periodic mode with the given initial count.
+///
+/// Zero stops the timer.
+#[inline]
+pub fn set_timer_initial_count(count: u32) {
+    write(reg::TIMER_INIT_COUNT, count);
+}
+
+/// Returns the current count of the timer in one-shot or periodic mode.
+#[inline]
+pub fn timer_current_count() -> u32 {
+    read(reg::TIMER_CURRENT_COUNT)
+}
+
+/// Arms the timer in TSC-deadline mode to fire when the TSC reaches
+/// `deadline`.
+///
+/// Zero disarms the timer.
+#[inline]
+pub fn set_tsc_deadline(deadline: u64) {
+    unsafe { msr::wrmsr(IA32_TSC_DEADLINE, deadline) }
+}
+
+/// Masks the timer interrupt.
+pub fn stop_timer() {
+    write(reg::LVT_TIMER, read(reg::LVT_TIMER) | LVT_MASKED);
+    write(reg::TIMER_INIT_COUNT, 0);
+}
+
+fn send_ipi_raw(dest: IpiDest, mode: DeliveryMode, vector: u8) {
+    let low = ICR_LEVEL_ASSERT | dest.shorthand() << 18 | (mode as u32) << 8 | vector as u32;
+    let apic_id = match dest {
+        IpiDest::Apic(id) => id,
+        _ => 0,
+    };
+    if is_x2apic() {
+        let icr = (apic_id as u64) << 32 | low as u64;
+        unsafe { msr::wrmsr(0x800 + reg::ICR_LOW, icr) }
+    } else {
+        write(reg::ICR_HIGH, apic_id << 24);
+        write(reg::ICR_LOW, low);
+        while read(reg::ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Sends an IPI with the given vector (fixed delivery mode).
+pub fn send_ipi(dest: IpiDest, vector: u8) {
+    send_ipi_raw(dest, DeliveryMode::Fixed, vector);
+}
+
+/// Sends a non-maskable interrupt (NMI) as an IPI.
+///
+/// The receivers handle it with the handlers registered in [`NMI`].
+///
+/// [`NMI`]: crate::trap::NMI
+pub fn send_nmi(dest: IpiDest) {
+    send_ipi_raw(dest, DeliveryMode::Nmi, 0);
+}
+
+/// Starts an application processor (AP) with the INIT-SIPI-SIPI sequence.
+///
+/// The AP starts executing in real mode at the physical address
+/// `start_page << 12`. `delay_us` is used to wait for the given microseconds
+/// between the IPIs.
+///
+/// # Safety
+///
+/// The startup code must be placed at the start page, and the target CPU must
+/// not be running.
+pub unsafe fn start_ap(apic_id: u32, start_page: u8, delay_us: impl Fn(u64)) {
+    let dest = IpiDest::Apic(apic_id);
+    send_ipi_raw(dest, DeliveryMode::Init, 0);
+    delay_us(10_000);
+    for _ in 0..2 {
+        send_ipi_raw(dest, DeliveryMode::StartUp, start_page);
+        delay_us(200);
+    }
+}
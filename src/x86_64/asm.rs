@@ -5,6 +5,9 @@ use core::arch::asm;
 use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
 use x86::{controlregs, msr, tlb};
 use x86_64::instructions::interrupts;
+use x86_64::instructions::tlb::{self as tlb_x86_64, InvPcidCommand, Pcid};
+
+use super::features::cpu_features;
 
 /// Allows the current CPU to respond to interrupts.
 #[inline]
@@ -83,11 +86,18 @@ pub fn read_kernel_page_table() -> PhysAddr {
 ///
 /// Note that the TLB will be **flushed** after this operation.
 ///
+/// If the `kpti` feature is enabled, it also resets the page table used in
+/// user space to the same one, see [`kpti::set_user_page_table`].
+///
+/// [`kpti::set_user_page_table`]: crate::kpti::set_user_page_table
+///
 /// # Safety
 ///
 /// This function is unsafe as it changes the virtual memory address space.
 #[inline]
 pub unsafe fn write_user_page_table(root_paddr: PhysAddr) {
+    #[cfg(feature = "kpti")]
+    super::kpti::set_kernel_page_table(root_paddr.as_usize());
     unsafe { controlregs::cr3_write(root_paddr.as_usize() as _) }
 }
 
@@ -118,24 +128,288 @@ pub fn flush_tlb(vaddr: Option<VirtAddr>) {
     } else {
         unsafe { tlb::flush_all() }
     }
+    #[cfg(feature = "kpti")]
+    super::kpti::flush_user_tlb(current_pcid(), vaddr.map(|vaddr| vaddr.as_usize()));
+}
+
+/// Flushes the TLB entries that map the given virtual address range.
+///
+/// `asid` is the PCID of the address space, which only matters if PCIDs are
+/// enabled (`CR4.PCIDE`). If it is [`None`] or the current PCID, `INVLPG` is
+/// used to flush the entries of the current address space and the global
+/// entries. Otherwise, `INVPCID` is used to flush the non-global entries of
+/// the given PCID. If the range covers more pages than
+/// [`flush_range_threshold`], the whole address space is flushed instead, and
+/// all global entries as well for the current one.
+///
+/// Only the TLB of the current CPU is flushed.
+///
+/// [`flush_range_threshold`]: crate::tlb::flush_range_threshold
+pub fn flush_tlb_range(start: VirtAddr, size: usize, asid: Option<usize>) {
+    let pages = crate::tlb::page_range(start.as_usize(), size);
+    let other_pcid = asid.filter(|&pcid| pcids_enabled() && pcid != current_pcid());
+    if pages.len() > crate::tlb::flush_range_threshold() {
+        match other_pcid {
+            Some(pcid) => flush_tlb_asid(pcid),
+            None => {
+                flush_tlb_global();
+                #[cfg(feature = "kpti")]
+                super::kpti::flush_user_tlb(current_pcid(), None);
+            }
+        }
+        return;
+    }
+    match other_pcid {
+        Some(pcid) if cpu_features().invpcid => {
+            for page in pages {
+                let vaddr = x86_64::VirtAddr::new_truncate((page << 12) as u64);
+                let cmd = InvPcidCommand::Address(vaddr, Pcid::new(pcid as u16).unwrap());
+                unsafe { tlb_x86_64::flush_pcid(cmd) }
+                #[cfg(feature = "kpti")]
+                super::kpti::flush_user_tlb(pcid, Some(page << 12));
+            }
+        }
+        Some(pcid) => flush_tlb_asid(pcid),
+        None => {
+            for page in pages {
+                unsafe { tlb::flush(page << 12) }
+                #[cfg(feature = "kpti")]
+                super::kpti::flush_user_tlb(current_pcid(), Some(page << 12));
+            }
+        }
+    }
+}
+
+/// Flushes all non-global TLB entries of the given PCID.
+///
+/// If PCIDs are not enabled (`CR4.PCIDE`), all non-global entries are
+/// flushed. If `INVPCID` is not supported, and the given PCID is not the
+/// current one, the entire TLB including global entries is flushed.
+///
+/// Only the TLB of the current CPU is flushed.
+pub fn flush_tlb_asid(asid: usize) {
+    #[cfg(feature = "kpti")]
+    super::kpti::flush_user_tlb(asid, None);
+    if !pcids_enabled() {
+        unsafe { tlb::flush_all() }
+    } else if cpu_features().invpcid {
+        let cmd = InvPcidCommand::Single(Pcid::new(asid as u16).unwrap());
+        unsafe { tlb_x86_64::flush_pcid(cmd) }
+    } else if asid == current_pcid() {
+        // Reloading CR3 flushes the current PCID if bit 63 is not set.
+        unsafe { controlregs::cr3_write(controlregs::cr3() & !(1 << 63)) }
+    } else {
+        flush_tlb_global();
+    }
+}
+
+/// Flushes all TLB entries of all PCIDs, including the global entries.
+pub(super) fn flush_tlb_global() {
+    if cpu_features().invpcid {
+        unsafe { tlb_x86_64::flush_pcid(InvPcidCommand::All) }
+    } else {
+        // Toggling CR4.PGE flushes all entries of all PCIDs.
+        unsafe {
+            let cr4 = controlregs::cr4();
+            controlregs::cr4_write(cr4 ^ controlregs::Cr4::CR4_ENABLE_GLOBAL_PAGES);
+            controlregs::cr4_write(cr4);
+        }
+    }
+}
+
+/// Returns whether PCIDs are enabled (`CR4.PCIDE`).
+#[inline]
+pub(super) fn pcids_enabled() -> bool {
+    unsafe { controlregs::cr4() }.contains(controlregs::Cr4::CR4_ENABLE_PCID)
+}
+
+/// Returns the current PCID in `CR3`.
+#[inline]
+fn current_pcid() -> usize {
+    (unsafe { controlregs::cr3() } & 0xfff) as usize
+}
+
+/// Performs the cache line operation `op` on each line of the given range.
+macro_rules! cache_range_op {
+    ($op:literal, $start:expr, $size:expr, $line:expr) => {{
+        let line = $line;
+        let start = $start.as_usize() & !(line - 1);
+        let end = $start.as_usize().saturating_add($size);
+        asm!("mfence");
+        for addr in (start..end).step_by(line) {
+            asm!(concat!($op, " [{}]"), in(reg) addr);
+        }
+        asm!("mfence");
+    }};
+}
+
+/// Writes back and invalidates the cache lines covering the given range, using
+/// `CLFLUSHOPT` if supported, or `CLFLUSH` otherwise.
+fn flush_cache_range(start: VirtAddr, size: usize) {
+    let features = cpu_features();
+    unsafe {
+        if features.clflushopt {
+            cache_range_op!("clflushopt", start, size, features.clflush_line_size)
+        } else {
+            cache_range_op!("clflush", start, size, features.clflush_line_size)
+        }
+    }
+}
+
+/// Cleans the data cache lines covering the given range, i.e., writes the
+/// dirty lines back to memory.
+///
+/// `CLWB` is used if supported, which may keep the lines in the cache.
+/// Otherwise, the lines are also invalidated, by `CLFLUSHOPT` or `CLFLUSH`.
+///
+/// Caches are coherent with DMA on x86_64, so it is only required for
+/// non-coherent agents, e.g., persistent memory.
+#[inline]
+pub fn dcache_clean_range(start: VirtAddr, size: usize) {
+    let features = cpu_features();
+    if features.clwb {
+        unsafe { cache_range_op!("clwb", start, size, features.clflush_line_size) }
+    } else {
+        flush_cache_range(start, size)
+    }
+}
+
+/// Invalidates the data cache lines covering the given range.
+///
+/// x86_64 cannot invalidate cache lines without writing them back, so it is
+/// the same as [`dcache_clean_invalidate_range`].
+///
+/// # Safety
+///
+/// This function is unsafe for consistency with other architectures, where
+/// the dirty data in the cache lines is discarded.
+#[inline]
+pub unsafe fn dcache_invalidate_range(start: VirtAddr, size: usize) {
+    flush_cache_range(start, size)
+}
+
+/// Cleans and invalidates the data cache lines covering the given range, using
+/// `CLFLUSHOPT` if supported, or `CLFLUSH` otherwise.
+#[inline]
+pub fn dcache_clean_invalidate_range(start: VirtAddr, size: usize) {
+    flush_cache_range(start, size)
+}
+
+/// Makes the instruction fetches from the given range coherent with the
+/// previous data writes, e.g., after loading or modifying code.
+///
+/// Instruction caches are coherent with data writes on x86_64, so only a
+/// compiler fence is needed. Cross-modifying code on other CPUs still requires
+/// them to execute a serializing instruction.
+#[inline]
+pub fn icache_sync_range(_start: VirtAddr, _size: usize) {
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+/// Writes back and invalidates all caches of the current CPU (`WBINVD`), e.g.,
+/// before powering it down.
+#[inline]
+pub fn dcache_clean_invalidate_all() {
+    unsafe { asm!("wbinvd") }
+}
+
+/// Invalidates all caches of the current CPU without writing them back
+/// (`INVD`).
+///
+/// # Safety
+///
+/// This function is unsafe as all dirty data in the caches is discarded.
+#[inline]
+pub unsafe fn dcache_invalidate_all() {
+    unsafe { asm!("invd") }
+}
+
+/// Whether `CR4.FSGSBASE` is set by [`init_fsgsbase`] on the current CPU,
+/// cached to avoid reading `CR4` on each access.
+///
+/// [`init_fsgsbase`]: crate::init::init_fsgsbase
+#[percpu::def_percpu]
+pub(super) static FSGSBASE_ENABLED: bool = false;
+
+/// Returns whether the `RDFSBASE`, `WRFSBASE`, `RDGSBASE` and `WRGSBASE`
+/// instructions are enabled on the current CPU (`CR4.FSGSBASE`).
+#[inline]
+pub(super) fn fsgsbase_enabled() -> bool {
+    unsafe { FSGSBASE_ENABLED.read_current_raw() }
 }
 
 /// Reads the thread pointer of the current CPU (`FS_BASE`).
 ///
-/// It is used to implement TLS (Thread Local Storage).
+/// It is used to implement TLS (Thread Local Storage). `RDFSBASE` is used if
+/// enabled by [`init_fsgsbase`], otherwise the `IA32_FS_BASE` MSR is read.
+/// The per-CPU data area must have been set up on the current CPU.
+///
+/// [`init_fsgsbase`]: crate::init::init_fsgsbase
 #[inline]
 pub fn read_thread_pointer() -> usize {
-    unsafe { msr::rdmsr(msr::IA32_FS_BASE) as usize }
+    if fsgsbase_enabled() {
+        unsafe { x86::bits64::segmentation::rdfsbase() as usize }
+    } else {
+        unsafe { msr::rdmsr(msr::IA32_FS_BASE) as usize }
+    }
 }
 
 /// Writes the thread pointer of the current CPU (`FS_BASE`).
 ///
-/// It is used to implement TLS (Thread Local Storage).
+/// It is used to implement TLS (Thread Local Storage). `WRFSBASE` is used if
+/// enabled by [`init_fsgsbase`], otherwise the `IA32_FS_BASE` MSR is written.
+/// The per-CPU data area must have been set up on the current CPU.
 ///
 /// # Safety
 ///
 /// This function is unsafe as it changes the CPU states.
+///
+/// [`init_fsgsbase`]: crate::init::init_fsgsbase
 #[inline]
 pub unsafe fn write_thread_pointer(fs_base: usize) {
-    unsafe { msr::wrmsr(msr::IA32_FS_BASE, fs_base as u64) }
+    if fsgsbase_enabled() {
+        unsafe { x86::bits64::segmentation::wrfsbase(fs_base as u64) }
+    } else {
+        unsafe { msr::wrmsr(msr::IA32_FS_BASE, fs_base as u64) }
+    }
+}
+
+/// Replaces the GS base of user space with the given one, and returns the old
+/// one.
+///
+/// In the kernel, the user GS base is kept in the `IA32_KERNEL_GSBASE` MSR. If
+/// `FSGSBASE` is enabled, it is swapped in by `SWAPGS` and accessed by
+/// `RDGSBASE`/`WRGSBASE` instead of the slower MSR accesses. IRQs are disabled
+/// in between, as the handlers would take the user GS base as the per-CPU
+/// base.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the CPU states.
+#[cfg(feature = "uspace")]
+#[inline]
+pub(super) unsafe fn swap_user_gs_base(gs_base: usize) -> usize {
+    if fsgsbase_enabled() {
+        let old: usize;
+        unsafe {
+            asm!(
+                "pushfq",
+                "cli",
+                "swapgs",
+                "rdgsbase {old}",
+                "wrgsbase {new}",
+                "swapgs",
+                "popfq",
+                old = out(reg) old,
+                new = in(reg) gs_base,
+            )
+        }
+        old
+    } else {
+        unsafe {
+            let old = msr::rdmsr(msr::IA32_KERNEL_GSBASE) as usize;
+            msr::wrmsr(msr::IA32_KERNEL_GSBASE, gs_base as u64);
+            old
+        }
+    }
 }
@@ -144,6 +144,61 @@ impl fmt::Debug for ExtendedState {
     }
 }
 
+/// FP control words of a task.
+///
+/// It is a lightweight alternative of [`ExtendedState`], which only preserves
+/// the x87 FPU control word and `MXCSR` across context switches (the `fp-ctrl`
+/// feature without `fp-simd`), so that the rounding modes and exception masks
+/// of a task do not leak into others.
+#[derive(Debug, Clone, Copy)]
+pub struct FpControlState {
+    /// x87 FPU control word.
+    pub fcw: u16,
+    /// SSE control and status register.
+    pub mxcsr: u32,
+}
+
+impl FpControlState {
+    /// Returns the control words with initialized values.
+    pub const fn default() -> Self {
+        Self {
+            fcw: 0x37f,
+            mxcsr: 0x1f80,
+        }
+    }
+}
+
+#[cfg(feature = "fp-ctrl")]
+impl FpControlState {
+    /// Saves the current control words from CPU to this structure.
+    #[inline]
+    pub fn save(&mut self) {
+        unsafe {
+            core::arch::asm!(
+                "fnstcw [{0}]",
+                "stmxcsr [{1}]",
+                in(reg) &mut self.fcw,
+                in(reg) &mut self.mxcsr,
+                options(nostack, preserves_flags),
+            )
+        }
+    }
+
+    /// Restores the control words from this structure to CPU.
+    #[inline]
+    pub fn restore(&self) {
+        unsafe {
+            core::arch::asm!(
+                "fldcw [{0}]",
+                "ldmxcsr [{1}]",
+                in(reg) &self.fcw,
+                in(reg) &self.mxcsr,
+                options(nostack, preserves_flags),
+            )
+        }
+    }
+}
+
 /// Saved hardware states of a task.
 ///
 /// The context usually includes:
@@ -177,9 +232,15 @@ pub struct TaskContext {
     /// Extended states, i.e., FP/SIMD states.
     #[cfg(feature = "fp-simd")]
     pub ext_state: ExtendedState,
+    /// FP control words, when the full extended states are not switched.
+    #[cfg(all(feature = "fp-ctrl", not(feature = "fp-simd")))]
+    pub fp_ctrl: FpControlState,
     /// The `CR3` register value, i.e., the page table root.
     #[cfg(feature = "uspace")]
     pub cr3: memory_addr::PhysAddr,
+    /// The page table root used in user space, see [`kpti`](crate::kpti).
+    #[cfg(feature = "kpti")]
+    pub user_cr3: memory_addr::PhysAddr,
 }
 
 impl TaskContext {
@@ -197,8 +258,12 @@ impl TaskContext {
             fs_base: 0,
             #[cfg(feature = "uspace")]
             cr3: crate::asm::read_kernel_page_table(),
+            #[cfg(feature = "kpti")]
+            user_cr3: crate::asm::read_kernel_page_table(),
             #[cfg(feature = "fp-simd")]
             ext_state: ExtendedState::default(),
+            #[cfg(all(feature = "fp-ctrl", not(feature = "fp-simd")))]
+            fp_ctrl: FpControlState::default(),
             #[cfg(feature = "uspace")]
             gs_base: 0,
         }
@@ -206,6 +271,10 @@ impl TaskContext {
 
     /// Initializes the context for a new task, with the given entry point and
     /// kernel stack.
+    ///
+    /// The entry function must never return, see [`init_with_arg`] otherwise.
+    ///
+    /// [`init_with_arg`]: TaskContext::init_with_arg
     pub fn init(&mut self, entry: usize, kstack_top: VirtAddr, tls_area: VirtAddr) {
         unsafe {
             // x86_64 calling convention: the stack must be 16-byte aligned before
@@ -226,25 +295,101 @@ impl TaskContext {
         self.fs_base = tls_area.as_usize();
     }
 
+    /// Initializes the context for a new task, which calls `entry(arg)` on the
+    /// given kernel stack.
+    ///
+    /// If the task is first entered by
+    /// [`switch_to_irqsave`](Self::switch_to_irqsave), the hook registered to
+    /// [`FINISH_SWITCH`](crate::task::FINISH_SWITCH) is called and IRQs are
+    /// enabled before the entry function. If the entry function returns, the
+    /// hook registered to
+    /// [`TASK_EXIT`](crate::task::TASK_EXIT) is called on the same stack.
+    pub fn init_with_arg(
+        &mut self,
+        entry: usize,
+        arg: usize,
+        kstack_top: VirtAddr,
+        tls_area: VirtAddr,
+    ) {
+        unsafe {
+            // The stack is 16-byte aligned after `context_switch` returns to
+            // `task_entry`, as it calls the entry function.
+            let frame_ptr = (kstack_top.as_mut_ptr() as *mut ContextSwitchFrame).sub(1);
+            core::ptr::write(
+                frame_ptr,
+                ContextSwitchFrame {
+                    r12: entry as _,
+                    r13: arg as _,
+                    rip: task_entry as *const () as u64,
+                    ..Default::default()
+                },
+            );
+            self.rsp = frame_ptr as u64;
+        }
+        self.kstack_top = kstack_top;
+        self.fs_base = tls_area.as_usize();
+    }
+
     /// Changes the page table root in this context.
     ///
     /// The hardware register for page table root (`CR3` for x86) will be
     /// updated to the next task's after [`Self::switch_to`].
+    ///
+    /// If the `kpti` feature is enabled, the page table used in user space is
+    /// also set to the same one, see [`Self::set_user_page_table_root`].
     #[cfg(feature = "uspace")]
     pub fn set_page_table_root(&mut self, cr3: memory_addr::PhysAddr) {
         self.cr3 = cr3;
+        #[cfg(feature = "kpti")]
+        {
+            self.user_cr3 = cr3;
+        }
+    }
+
+    /// Changes the page table root used in user space in this context, which
+    /// maps only the user memory and the pages required by the entry code.
+    ///
+    /// It takes effect after [`Self::switch_to`], see
+    /// [`kpti::set_user_page_table`](crate::kpti::set_user_page_table).
+    #[cfg(feature = "kpti")]
+    pub fn set_user_page_table_root(&mut self, root: memory_addr::PhysAddr) {
+        self.user_cr3 = root;
     }
 
     /// Switches to another task.
     ///
     /// It first saves the current task's context from CPU to this place, and then
     /// restores the next task's context from `next_ctx` to CPU.
+    ///
+    /// It does not change the IRQ state. However, if the current task is
+    /// switched back by [`switch_to_irqsave`](Self::switch_to_irqsave), the
+    /// hook registered to [`FINISH_SWITCH`](crate::task::FINISH_SWITCH) is
+    /// called, and IRQs are restored to the state before this function was
+    /// called.
     pub fn switch_to(&mut self, next_ctx: &Self) {
+        let irqs_were_enabled = crate::asm::irqs_enabled();
+        if self.switch_to_impl(next_ctx, false) {
+            crate::task::finish_switch(irqs_were_enabled);
+        }
+    }
+
+    /// Switches to another task, where `irqsave` is passed to the next task,
+    /// i.e., to the entry trampoline if it is newly created, or as the return
+    /// value of this function in the next task otherwise.
+    ///
+    /// Returns the `irqsave` argument of the switch that resumes the current
+    /// task (`false` if resumed by [`Self::load`]).
+    pub(crate) fn switch_to_impl(&mut self, next_ctx: &Self, irqsave: bool) -> bool {
         #[cfg(feature = "fp-simd")]
         {
             self.ext_state.save();
             next_ctx.ext_state.restore();
         }
+        #[cfg(all(feature = "fp-ctrl", not(feature = "fp-simd")))]
+        {
+            self.fp_ctrl.save();
+            next_ctx.fp_ctrl.restore();
+        }
         #[cfg(any(feature = "tls", feature = "uspace"))]
         unsafe {
             self.fs_base = crate::asm::read_thread_pointer();
@@ -253,20 +398,192 @@ impl TaskContext {
         #[cfg(feature = "uspace")]
         unsafe {
             // Switch gs base for user space.
-            self.gs_base = x86::msr::rdmsr(x86::msr::IA32_KERNEL_GSBASE) as usize;
-            x86::msr::wrmsr(x86::msr::IA32_KERNEL_GSBASE, next_ctx.gs_base as u64);
+            self.gs_base = crate::asm::swap_user_gs_base(next_ctx.gs_base);
             super::gdt::write_tss_rsp0(next_ctx.kstack_top);
+            #[cfg(feature = "tlb-shootdown")]
+            crate::tlb::set_active_root(next_ctx.cr3);
             if next_ctx.cr3 != self.cr3 {
                 crate::asm::write_user_page_table(next_ctx.cr3);
                 // writing to CR3 has flushed the TLB
             }
+            #[cfg(feature = "kpti")]
+            super::kpti::set_user_page_table(next_ctx.user_cr3);
+        }
+        unsafe { context_switch(&mut self.rsp, &next_ctx.rsp, irqsave) }
+    }
+
+    /// Restores this context to CPU and jumps to it, without saving the
+    /// current context.
+    ///
+    /// It can be used to enter the first task on a CPU.
+    ///
+    /// # Safety
+    ///
+    /// The context must be initialized by [`init`] or saved by [`switch_to`].
+    /// The current stack is abandoned.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`switch_to`]: TaskContext::switch_to
+    pub unsafe fn load(&self) -> ! {
+        unsafe { self.load_from(None) }
+    }
+
+    /// Switches to another task without saving the current task's context.
+    ///
+    /// It can be used when the current task is exiting, as the current stack
+    /// is never touched after switching, so it can be freed by the next task.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`load`](TaskContext::load).
+    pub unsafe fn switch_to_no_save(&self, next_ctx: &Self) -> ! {
+        unsafe { next_ctx.load_from(Some(self)) }
+    }
+
+    #[cfg_attr(not(feature = "uspace"), allow(unused_variables))]
+    unsafe fn load_from(&self, prev: Option<&Self>) -> ! {
+        #[cfg(feature = "fp-simd")]
+        self.ext_state.restore();
+        #[cfg(all(feature = "fp-ctrl", not(feature = "fp-simd")))]
+        self.fp_ctrl.restore();
+        #[cfg(any(feature = "tls", feature = "uspace"))]
+        unsafe {
+            crate::asm::write_thread_pointer(self.fs_base);
+        }
+        #[cfg(feature = "uspace")]
+        unsafe {
+            crate::asm::swap_user_gs_base(self.gs_base);
+            super::gdt::write_tss_rsp0(self.kstack_top);
+            let cur_cr3 = prev.map_or_else(crate::asm::read_user_page_table, |p| p.cr3);
+            #[cfg(feature = "tlb-shootdown")]
+            crate::tlb::set_active_root(self.cr3);
+            if self.cr3 != cur_cr3 {
+                crate::asm::write_user_page_table(self.cr3);
+            }
+            #[cfg(feature = "kpti")]
+            super::kpti::set_user_page_table(self.user_cr3);
         }
-        unsafe { context_switch(&mut self.rsp, &next_ctx.rsp) }
+        unsafe { context_load(&self.rsp) }
     }
 }
 
+/// A minimal context for stackful coroutines (fibers).
+///
+/// Unlike [`TaskContext`], it only holds the callee-saved registers, which are
+/// pushed to the fiber's own stack on switching. FP/SIMD states, the thread
+/// pointer and the page table root are not touched.
+///
+/// When the entry function of a fiber returns, the fiber switches back to the
+/// context that most recently switched to it.
+#[derive(Debug, Default)]
+pub struct FiberContext {
+    /// `RSP` after all callee-saved registers are pushed.
+    rsp: u64,
+    /// Slot on the fiber stack that records the context to resume when the
+    /// fiber finishes.
+    resume_slot: usize,
+}
+
+impl FiberContext {
+    /// Creates an empty context.
+    ///
+    /// It can be used as the context of the caller that starts fibers, which
+    /// will be filled by [`switch`](FiberContext::switch).
+    pub const fn new() -> Self {
+        Self {
+            rsp: 0,
+            resume_slot: 0,
+        }
+    }
+
+    /// Initializes the context to run `entry(arg)` on the given stack.
+    ///
+    /// # Safety
+    ///
+    /// `stack_top` must be the top of a stack that is valid for writes and
+    /// large enough for `entry`. The stack must not be used for other purposes
+    /// until the fiber finishes.
+    pub unsafe fn make_context(
+        &mut self,
+        stack_top: VirtAddr,
+        entry: extern "C" fn(usize),
+        arg: usize,
+    ) {
+        unsafe {
+            // Keep the stack 16-byte aligned when `entry_trampoline` calls the
+            // entry function.
+            let resume_slot = (stack_top.as_mut_ptr() as *mut u64).sub(2);
+            resume_slot.write(0);
+            let frame_ptr = (resume_slot as *mut ContextSwitchFrame).sub(1);
+            core::ptr::write(
+                frame_ptr,
+                ContextSwitchFrame {
+                    r12: entry as usize as u64,
+                    r13: arg as _,
+                    r14: fiber_exit as *const () as u64,
+                    r15: resume_slot as u64,
+                    rip: entry_trampoline as *const () as u64,
+                    ..Default::default()
+                },
+            );
+            self.rsp = frame_ptr as u64;
+            self.resume_slot = resume_slot as usize;
+        }
+    }
+
+    /// Switches to another fiber.
+    ///
+    /// It saves the callee-saved registers to this place, and then restores
+    /// the ones of `next_ctx`.
+    pub fn switch(&mut self, next_ctx: &Self) {
+        if next_ctx.resume_slot != 0 {
+            unsafe { (next_ctx.resume_slot as *mut usize).write(self as *mut _ as usize) };
+        }
+        unsafe { context_switch(&mut self.rsp, &next_ctx.rsp, false) };
+    }
+}
+
+/// Resumes the context recorded in `resume_slot` after a fiber finishes.
+unsafe extern "C" fn fiber_exit(resume_slot: *const *const FiberContext) -> ! {
+    unsafe { context_load(&(**resume_slot).rsp) }
+}
+
+/// Calls `r12(r13)`, then `r14(r15)` which must not return.
 #[unsafe(naked)]
-unsafe extern "C" fn context_switch(_current_stack: &mut u64, _next_stack: &u64) {
+unsafe extern "C" fn entry_trampoline() -> ! {
+    naked_asm!(
+        "
+        mov     rdi, r13
+        call    r12
+        mov     rdi, r15
+        call    r14
+        ud2",
+    )
+}
+
+/// Finishes the context switch with the `irqsave` argument of `context_switch`
+/// left in `rdx` (cleared by `context_load`), then calls `r12(r13)` and exits the task.
+#[unsafe(naked)]
+unsafe extern "C" fn task_entry() -> ! {
+    naked_asm!(
+        "
+        mov     edi, edx
+        call    {finish_switch}
+        mov     rdi, r13
+        call    r12
+        call    {task_exit}
+        ud2",
+        finish_switch = sym crate::task::task_entry_finish_switch,
+        task_exit = sym crate::task::task_exit,
+    )
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn context_switch(
+    _current_stack: &mut u64,
+    _next_stack: &u64,
+    _irqsave: bool,
+) -> bool {
     naked_asm!(
         "
         .code64
@@ -285,6 +602,25 @@ unsafe extern "C" fn context_switch(_current_stack: &mut u64, _next_stack: &u64)
         pop     r12
         pop     rbx
         pop     rbp
+        mov     eax, edx
+        ret",
+    )
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn context_load(_next_stack: &u64) -> ! {
+    naked_asm!(
+        "
+        .code64
+        xor     eax, eax
+        xor     edx, edx
+        mov     rsp, [rdi]
+        pop     r15
+        pop     r14
+        pop     r13
+        pop     r12
+        pop     rbx
+        pop     rbp
         ret",
     )
 }
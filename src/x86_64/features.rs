@@ -0,0 +1,151 @@
+//! CPU feature detection based on `CPUID`.
+//!
+//! The features are decoded on each CPU by [`init_percpu`] (or [`init_trap`])
+//! and stored in the per-CPU data area, so CPUs with different features (e.g.,
+//! hybrid CPUs with different `CPUID` leaves) are distinguished.
+//! [`cpu_features`] returns the features of the current CPU.
+//!
+//! Before that, [`cpu_features`] falls back to [`boot_cpu_features`], which are
+//! decoded once on the first call, usually on the boot CPU.
+//!
+//! [`init_percpu`]: crate::init::init_percpu
+//! [`init_trap`]: crate::init::init_trap
+
+use lazyinit::LazyInit;
+use x86::cpuid::CpuId;
+
+/// CPU features reported by `CPUID`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuFeatures {
+    /// `FXSAVE` and `FXRSTOR` instructions (`CPUID.01H:EDX[24]`).
+    pub fxsr: bool,
+    /// Page attribute table (`CPUID.01H:EDX[16]`).
+    pub pat: bool,
+    /// `XSAVE` family instructions (`CPUID.01H:ECX[26]`).
+    pub xsave: bool,
+    /// Process-context identifiers (`CPUID.01H:ECX[17]`).
+    pub pcid: bool,
+    /// `INVPCID` instruction (`CPUID.(EAX=07H,ECX=0):EBX[10]`).
+    pub invpcid: bool,
+    /// x2APIC mode of the local APIC (`CPUID.01H:ECX[21]`).
+    pub x2apic: bool,
+    /// TSC-deadline mode of the local APIC timer (`CPUID.01H:ECX[24]`).
+    pub tsc_deadline: bool,
+    /// Supervisor-mode execution prevention (`CPUID.(EAX=07H,ECX=0):EBX[7]`).
+    pub smep: bool,
+    /// Supervisor-mode access prevention (`CPUID.(EAX=07H,ECX=0):EBX[20]`).
+    pub smap: bool,
+    /// User-mode instruction prevention (`CPUID.(EAX=07H,ECX=0):ECX[2]`).
+    pub umip: bool,
+    /// `RDFSBASE`/`WRFSBASE`/`RDGSBASE`/`WRGSBASE` instructions
+    /// (`CPUID.(EAX=07H,ECX=0):EBX[0]`).
+    pub fsgsbase: bool,
+    /// `CLFLUSHOPT` instruction (`CPUID.(EAX=07H,ECX=0):EBX[23]`).
+    pub clflushopt: bool,
+    /// `CLWB` instruction (`CPUID.(EAX=07H,ECX=0):EBX[24]`).
+    pub clwb: bool,
+    /// Execute-disable bit in page table entries (`CPUID.80000001H:EDX[20]`).
+    pub nx: bool,
+    /// 1 GiB pages (`CPUID.80000001H:EDX[26]`).
+    pub page_1gb: bool,
+    /// `RDTSCP` instruction (`CPUID.80000001H:EDX[27]`).
+    pub rdtscp: bool,
+    /// Invariant TSC, which runs at a constant rate in all ACPI P-, C- and
+    /// T-states (`CPUID.80000007H:EDX[8]`).
+    pub invariant_tsc: bool,
+    /// The cache line size for `CLFLUSH` in bytes (`CPUID.01H:EBX[15:8]`
+    /// multiplied by 8). It is 64 if not reported or not a power of two.
+    pub clflush_line_size: usize,
+}
+
+impl CpuFeatures {
+    /// Returns the largest page size supported by the MMU, i.e., 1 GiB if
+    /// [`page_1gb`](Self::page_1gb) is set, or 2 MiB otherwise.
+    pub const fn max_page_size(&self) -> usize {
+        if self.page_1gb {
+            0x4000_0000
+        } else {
+            0x20_0000
+        }
+    }
+
+    /// Decodes the features of the current CPU by executing `CPUID`.
+    pub fn detect() -> Self {
+        let cpuid = CpuId::new();
+        let info = cpuid.get_feature_info();
+        let ext = cpuid.get_extended_feature_info();
+        let ext_fn = cpuid.get_extended_processor_and_feature_identifiers();
+        Self {
+            fxsr: info.as_ref().is_some_and(|i| i.has_fxsave_fxstor()),
+            pat: info.as_ref().is_some_and(|i| i.has_pat()),
+            xsave: info.as_ref().is_some_and(|i| i.has_xsave()),
+            pcid: info.as_ref().is_some_and(|i| i.has_pcid()),
+            invpcid: ext.as_ref().is_some_and(|e| e.has_invpcid()),
+            x2apic: info.as_ref().is_some_and(|i| i.has_x2apic()),
+            tsc_deadline: info.as_ref().is_some_and(|i| i.has_tsc_deadline()),
+            smep: ext.as_ref().is_some_and(|e| e.has_smep()),
+            smap: ext.as_ref().is_some_and(|e| e.has_smap()),
+            umip: ext.as_ref().is_some_and(|e| e.has_umip()),
+            fsgsbase: ext.as_ref().is_some_and(|e| e.has_fsgsbase()),
+            clflushopt: ext.as_ref().is_some_and(|e| e.has_clflushopt()),
+            clwb: ext.as_ref().is_some_and(|e| e.has_clwb()),
+            nx: ext_fn.as_ref().is_some_and(|e| e.has_execute_disable()),
+            page_1gb: ext_fn.as_ref().is_some_and(|e| e.has_1gib_pages()),
+            rdtscp: ext_fn.as_ref().is_some_and(|e| e.has_rdtscp()),
+            invariant_tsc: cpuid
+                .get_advanced_power_mgmt_info()
+                .is_some_and(|a| a.has_invariant_tsc()),
+            // Some hypervisors report zero in CPUID.01H:EBX[15:8].
+            clflush_line_size: info
+                .as_ref()
+                .map(|i| i.cflush_cache_line_size() as usize * 8)
+                .filter(|size| size.is_power_of_two())
+                .unwrap_or(64),
+        }
+    }
+}
+
+/// The features of the current CPU, or `None` if not decoded yet.
+#[percpu::def_percpu]
+static CPU_FEATURES: Option<CpuFeatures> = None;
+
+/// Decodes the features of the current CPU and stores them in the per-CPU data
+/// area, if not done yet.
+pub(super) fn init_percpu_features() {
+    unsafe {
+        let features = CPU_FEATURES.current_ref_mut_raw();
+        if features.is_none() {
+            *features = Some(CpuFeatures::detect());
+        }
+    }
+}
+
+/// Returns the features of the current CPU.
+///
+/// They are decoded on the current CPU by [`init_percpu`] or [`init_trap`].
+/// Before that, the features returned by [`boot_cpu_features`] are returned.
+/// The per-CPU data area must have been set up, i.e., [`percpu::init`] and
+/// [`percpu::init_percpu_reg`] must have been called on the current CPU.
+///
+/// [`init_percpu`]: crate::init::init_percpu
+/// [`init_trap`]: crate::init::init_trap
+/// [`percpu::init`]: https://docs.rs/percpu/latest/percpu/fn.init.html
+/// [`percpu::init_percpu_reg`]: https://docs.rs/percpu/latest/percpu/fn.init_percpu_reg.html
+#[inline]
+pub fn cpu_features() -> &'static CpuFeatures {
+    unsafe { CPU_FEATURES.current_ref_raw() }
+        .as_ref()
+        .unwrap_or_else(|| boot_cpu_features())
+}
+
+/// Returns the features of the CPU that first calls this function, usually the
+/// boot CPU.
+///
+/// It does not use the per-CPU data, so it can be used on the boot CPU before
+/// the per-CPU data areas are initialized.
+pub fn boot_cpu_features() -> &'static CpuFeatures {
+    static FEATURES: LazyInit<CpuFeatures> = LazyInit::new();
+    FEATURES
+        .call_once(CpuFeatures::detect)
+        .unwrap_or_else(|| FEATURES.get().unwrap())
+}
@@ -1,4 +1,5 @@
 use core::fmt;
+use core::mem::offset_of;
 
 use lazyinit::LazyInit;
 use x86_64::instructions::tables::{lgdt, load_tss};
@@ -7,12 +8,120 @@ use x86_64::structures::gdt::{Descriptor, DescriptorFlags};
 use x86_64::structures::{tss::TaskStateSegment, DescriptorTablePointer};
 use x86_64::{addr::VirtAddr, PrivilegeLevel};
 
+/// Index of the IST stack for double faults (`#DF`).
+pub(super) const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Index of the IST stack for non-maskable interrupts (NMI).
+pub(super) const NMI_IST_INDEX: u16 = 1;
+/// Index of the IST stack for machine checks (`#MC`).
+pub(super) const MACHINE_CHECK_IST_INDEX: u16 = 2;
+/// Index of the IST stack for debug exceptions (`#DB`).
+pub(super) const DEBUG_IST_INDEX: u16 = 3;
+
+const NUM_IST_STACKS: usize = 4;
+const IST_STACK_SIZE: usize = 0x2000;
+
+/// The size of the slot reserved at the top of each IST stack, which holds the
+/// per-CPU data base for the paranoid entry code.
+const IST_TOP_SLOT_SIZE: usize = 16;
+
+#[cfg(feature = "kpti")]
+const ENTRY_STACK_SIZE: usize = 512;
+
+#[repr(C, align(16))]
+struct IstStacks([[u8; IST_STACK_SIZE]; NUM_IST_STACKS]);
+
+#[cfg(feature = "kpti")]
+#[repr(C, align(16))]
+struct EntryStack([u8; ENTRY_STACK_SIZE]);
+
+/// Per-CPU data accessed by the CPU and the entry code on traps and syscalls.
+///
+/// With the `kpti` feature, it must be mapped in the user page tables, since it
+/// is accessed before switching to the kernel page table (see
+/// [`entry_area_range`](crate::kpti::entry_area_range)). Per-CPU data areas are
+/// not page-aligned, so it is padded by a page on both sides, and the pages
+/// between the paddings contain no other per-CPU data.
+#[repr(C)]
+pub(super) struct CpuEntryArea {
+    #[cfg(feature = "kpti")]
+    _pad_start: [u8; memory_addr::PAGE_SIZE_4K],
+    tss: TaskStateSegment,
+    gdt: LazyInit<GdtStruct>,
+    /// The `CR3` value of the kernel page table.
+    pub(super) kernel_cr3: usize,
+    /// The `CR3` value of the user page table.
+    pub(super) user_cr3: usize,
+    /// The kernel stack pointer that the entry code switches to, if `RSP0`
+    /// points to the entry stack.
+    pub(super) kernel_rsp0: usize,
+    /// Scratch space for the user stack pointer in the syscall entry.
+    user_rsp: usize,
+    /// The stack used by the entry code before switching to the kernel page
+    /// table. `RSP0` of the TSS points to its top.
+    #[cfg(feature = "kpti")]
+    entry_stack: EntryStack,
+    /// The interrupt stacks of the interrupt stack table (IST) in the TSS, so
+    /// that some exceptions are handled on a known good stack, even if the
+    /// kernel stack is corrupted or overflowed.
+    ist_stacks: IstStacks,
+    #[cfg(feature = "kpti")]
+    _pad_end: [u8; memory_addr::PAGE_SIZE_4K],
+}
+
 #[unsafe(no_mangle)]
 #[percpu::def_percpu]
-static TSS: TaskStateSegment = TaskStateSegment::new();
+static CPU_ENTRY_AREA: CpuEntryArea = CpuEntryArea {
+    #[cfg(feature = "kpti")]
+    _pad_start: [0; memory_addr::PAGE_SIZE_4K],
+    tss: TaskStateSegment::new(),
+    gdt: LazyInit::new(),
+    kernel_cr3: 0,
+    user_cr3: 0,
+    kernel_rsp0: 0,
+    user_rsp: 0,
+    #[cfg(feature = "kpti")]
+    entry_stack: EntryStack([0; ENTRY_STACK_SIZE]),
+    ist_stacks: IstStacks([[0; IST_STACK_SIZE]; NUM_IST_STACKS]),
+    #[cfg(feature = "kpti")]
+    _pad_end: [0; memory_addr::PAGE_SIZE_4K],
+};
 
-#[percpu::def_percpu]
-static GDT: LazyInit<GdtStruct> = LazyInit::new();
+/// Offsets of the fields in `CPU_ENTRY_AREA` (`__PERCPU_CPU_ENTRY_AREA`) used
+/// by the entry code.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub(super) mod entry_offsets {
+    use super::{offset_of, CpuEntryArea, TaskStateSegment};
+
+    pub const TSS_RSP0: usize =
+        offset_of!(CpuEntryArea, tss) + offset_of!(TaskStateSegment, privilege_stack_table);
+    pub const KERNEL_CR3: usize = offset_of!(CpuEntryArea, kernel_cr3);
+    pub const USER_CR3: usize = offset_of!(CpuEntryArea, user_cr3);
+    pub const KERNEL_RSP0: usize = offset_of!(CpuEntryArea, kernel_rsp0);
+    #[cfg(feature = "uspace")]
+    pub const USER_RSP: usize = offset_of!(CpuEntryArea, user_rsp);
+}
+
+/// Returns the entry area of the current CPU.
+pub(super) fn entry_area() -> *mut CpuEntryArea {
+    unsafe { CPU_ENTRY_AREA.current_ptr() as *mut CpuEntryArea }
+}
+
+/// Returns the address range of the entry area of the given CPU, excluding the
+/// paddings.
+#[cfg(feature = "kpti")]
+pub(super) fn entry_area_range(cpu_id: usize) -> core::ops::Range<memory_addr::VirtAddr> {
+    let area = unsafe { CPU_ENTRY_AREA.remote_ptr(cpu_id) } as usize;
+    let start = area + offset_of!(CpuEntryArea, tss);
+    let end = area + offset_of!(CpuEntryArea, _pad_end);
+    va!(start)..va!(end)
+}
+
+/// Returns the stack top of the entry stack of the current CPU.
+#[cfg(feature = "kpti")]
+pub(super) fn entry_stack_top() -> memory_addr::VirtAddr {
+    let stack = unsafe { &raw const (*entry_area()).entry_stack };
+    va!(stack as usize + ENTRY_STACK_SIZE)
+}
 
 /// A wrapper of the Global Descriptor Table (GDT) with maximum 16 entries.
 #[repr(align(16))]
@@ -101,29 +210,76 @@ impl fmt::Debug for GdtStruct {
 
 /// Initializes the per-CPU TSS and GDT structures and loads them into the
 /// current CPU.
+///
+/// The interrupt stack table (IST) of the TSS is filled with the per-CPU IST
+/// stacks, which are used by double faults, NMIs, machine checks and debug
+/// exceptions. The per-CPU data base is stored at the top of each of them.
 pub fn init_gdt() {
+    let percpu_base = percpu::read_percpu_reg();
     unsafe {
-        let gdt = GDT.current_ref_raw();
-        gdt.init_once(GdtStruct::new(TSS.current_ref_raw()));
+        let area = &mut *entry_area();
+        for (i, stack) in area.ist_stacks.0.iter_mut().enumerate() {
+            let top = stack.as_mut_ptr_range().end.sub(IST_TOP_SLOT_SIZE);
+            (top as *mut usize).write(percpu_base);
+            area.tss.interrupt_stack_table[i] = VirtAddr::from_ptr(top);
+        }
+        let gdt = &(*entry_area()).gdt;
+        gdt.init_once(GdtStruct::new(&(*entry_area()).tss));
         gdt.load();
         gdt.load_tss();
     }
 }
 
 /// Returns the stack pointer for privilege level 0 (RSP0) of the current TSS.
+///
+/// If the `kpti` feature is enabled, RSP0 points to the per-CPU entry stack,
+/// and the kernel stack pointer that the entry code switches to is returned
+/// instead.
 #[cfg(feature = "uspace")]
 pub(crate) fn read_tss_rsp0() -> memory_addr::VirtAddr {
-    let tss = unsafe { TSS.current_ref_raw() };
-    memory_addr::VirtAddr::from(tss.privilege_stack_table[0].as_u64() as usize)
+    let area = unsafe { &*entry_area() };
+    if cfg!(feature = "kpti") {
+        va!(area.kernel_rsp0)
+    } else {
+        va!(area.tss.privilege_stack_table[0].as_u64() as usize)
+    }
 }
 
 /// Sets the stack pointer for privilege level 0 (RSP0) of the current TSS.
 ///
+/// If the `kpti` feature is enabled, the kernel stack pointer that the entry
+/// code switches to is set instead.
+///
 /// # Safety
 ///
 /// Must be called after initialization and preemption is disabled.
 #[cfg(feature = "uspace")]
 pub(crate) unsafe fn write_tss_rsp0(rsp0: memory_addr::VirtAddr) {
-    let tss = unsafe { TSS.current_ref_mut_raw() };
+    if cfg!(feature = "kpti") {
+        unsafe { (*entry_area()).kernel_rsp0 = rsp0.as_usize() };
+    } else {
+        unsafe { write_tss_rsp0_raw(rsp0) }
+    }
+}
+
+/// Sets RSP0 of the current TSS.
+///
+/// # Safety
+///
+/// Must be called after initialization and preemption is disabled.
+#[cfg(feature = "uspace")]
+pub(super) unsafe fn write_tss_rsp0_raw(rsp0: memory_addr::VirtAddr) {
+    let tss = unsafe { &mut (*entry_area()).tss };
     tss.privilege_stack_table[0] = VirtAddr::new_truncate(rsp0.as_usize() as u64);
 }
+
+/// Sets the stack of the given interrupt stack table (IST) index in the
+/// current TSS.
+///
+/// # Safety
+///
+/// Must be called after initialization and preemption is disabled.
+pub(super) unsafe fn write_tss_ist(index: u16, stack_top: memory_addr::VirtAddr) {
+    let tss = unsafe { &mut (*entry_area()).tss };
+    tss.interrupt_stack_table[index as usize] = VirtAddr::new_truncate(stack_top.as_usize() as u64);
+}
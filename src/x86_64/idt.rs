@@ -1,18 +1,34 @@
-use core::fmt;
+use core::{cell::UnsafeCell, fmt};
 
 use lazyinit::LazyInit;
 use x86_64::addr::VirtAddr;
 use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable};
 use x86_64::structures::DescriptorTablePointer;
 
+use super::vector::{vector_config, VectorConfig};
+
 const NUM_INT: usize = 256;
 
-static IDT: LazyInit<IdtStruct> = LazyInit::new();
+pub(super) static IDT: LazyInit<IdtStruct> = LazyInit::new();
 
 /// A wrapper of the Interrupt Descriptor Table (IDT).
 #[repr(transparent)]
 pub struct IdtStruct {
-    table: InterruptDescriptorTable,
+    table: UnsafeCell<InterruptDescriptorTable>,
+}
+
+// The entries are only modified by `update_entry`, whose callers ensure that
+// the interrupts of the entry do not occur meanwhile.
+unsafe impl Sync for IdtStruct {}
+
+fn set_entry(entry: &mut Entry<HandlerFunc>, handler: u64, config: VectorConfig) {
+    let opt = unsafe { entry.set_handler_addr(VirtAddr::new(handler)) };
+    if config.user {
+        opt.set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+    }
+    if let Some(index) = config.ist_index {
+        unsafe { opt.set_stack_index(index) };
+    }
 }
 
 impl IdtStruct {
@@ -24,32 +40,27 @@ impl IdtStruct {
             #[link_name = "trap_handler_table"]
             static ENTRIES: [extern "C" fn(); NUM_INT];
         }
-        let mut idt = Self {
-            table: InterruptDescriptorTable::new(),
-        };
-
+        let mut table = InterruptDescriptorTable::new();
         let entries = unsafe {
             core::slice::from_raw_parts_mut(
-                &mut idt.table as *mut _ as *mut Entry<HandlerFunc>,
+                &mut table as *mut _ as *mut Entry<HandlerFunc>,
                 NUM_INT,
             )
         };
         for i in 0..NUM_INT {
-            #[allow(clippy::missing_transmute_annotations)]
-            let opt = entries[i].set_handler_fn(unsafe { core::mem::transmute(ENTRIES[i]) });
-            if i == 0x3 || i == 0x80 {
-                // enable user space breakpoints and legacy int 0x80 syscall
-                opt.set_privilege_level(x86_64::PrivilegeLevel::Ring3);
-            }
+            let handler = unsafe { ENTRIES[i] } as *const () as usize as u64;
+            set_entry(&mut entries[i], handler, vector_config(i as u8));
+        }
+        Self {
+            table: UnsafeCell::new(table),
         }
-        idt
     }
 
     /// Returns the IDT pointer (base and limit) that can be used in the `lidt`
     /// instruction.
     pub fn pointer(&self) -> DescriptorTablePointer {
         DescriptorTablePointer {
-            base: VirtAddr::new(&self.table as *const _ as u64),
+            base: VirtAddr::new(self.table.get() as u64),
             limit: (core::mem::size_of::<InterruptDescriptorTable>() - 1) as u16,
         }
     }
@@ -61,7 +72,7 @@ impl IdtStruct {
     /// This function is unsafe because it manipulates the CPU's privileged
     /// states.
     pub unsafe fn load(&'static self) {
-        self.table.load();
+        unsafe { (*self.table.get()).load() };
     }
 }
 
@@ -69,7 +80,7 @@ impl fmt::Debug for IdtStruct {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("IdtStruct")
             .field("pointer", &self.pointer())
-            .field("table", &self.table)
+            .field("table", unsafe { &*self.table.get() })
             .finish()
     }
 }
@@ -79,3 +90,17 @@ pub fn init_idt() {
     IDT.call_once(IdtStruct::new);
     unsafe { IDT.load() };
 }
+
+/// Updates the entry of the given vector in the global IDT with the given
+/// configuration, if the IDT has been initialized.
+pub(super) fn update_entry(vector: u8, config: VectorConfig) {
+    if let Some(idt) = IDT.get() {
+        // The IDT is in use by all CPUs, so only the single entry is written,
+        // without borrowing the whole table mutably.
+        let ptr = unsafe { (idt.table.get() as *mut Entry<HandlerFunc>).add(vector as usize) };
+        let mut entry = unsafe { ptr.read_volatile() };
+        let handler = entry.handler_addr().as_u64();
+        set_entry(&mut entry, handler, config);
+        unsafe { ptr.write_volatile(entry) };
+    }
+}
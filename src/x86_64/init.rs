@@ -4,25 +4,148 @@ pub use super::gdt::init_gdt;
 pub use super::idt::init_idt;
 
 #[cfg(feature = "uspace")]
-pub use super::syscall::init_syscall;
+pub use super::syscall::{init_syscall, init_sysenter};
+
+#[cfg(feature = "uspace")]
+pub use super::uaccess::init_uaccess_protection;
+
+#[cfg(feature = "kpti")]
+pub use super::kpti::init_kpti;
+
+#[cfg(feature = "apic")]
+pub use super::apic::init_local_apic;
 
 /// Initializes the per-CPU data structures.
 ///
-/// It calls the initialization function of the [`percpu`] crate. It (or other
+/// It calls the initialization function of the [`percpu`] crate, and decodes
+/// the features of the current CPU (see [`cpu_features`]). It (or other
 /// alternative initialization) should be called before [`init_trap`].
 ///
+/// [`cpu_features`]: crate::features::cpu_features
+///
 /// [`percpu`]: https://docs.rs/percpu/latest/percpu/index.html
 pub fn init_percpu(cpu_id: usize) {
     percpu::init();
     percpu::init_percpu_reg(cpu_id);
+    super::features::init_percpu_features();
+}
+
+/// Initializes the page attribute table (PAT) on the current CPU.
+///
+/// It sets the `IA32_PAT` MSR to [`MemoryType::PAT_VALUE`], so that the
+/// attribute bits returned by [`MemoryType::pte_bits`] take effect. It should
+/// be called on each CPU before any write-combining mappings are used.
+///
+/// The MSR is written with IRQs and caches disabled, and the caches and TLB
+/// flushed before and after, as required by the Intel SDM (Vol. 3A, 11.12.4).
+///
+/// It does nothing but logs a warning if PAT is not supported.
+///
+/// [`MemoryType::PAT_VALUE`]: crate::mem_type::MemoryType::PAT_VALUE
+/// [`MemoryType::pte_bits`]: crate::mem_type::MemoryType::pte_bits
+pub fn init_pat() {
+    use crate::mem_type::MemoryType;
+    use x86::controlregs::{cr0, cr0_write, Cr0};
+    if !super::features::cpu_features().pat {
+        warn!("PAT is not supported, skip initializing PAT");
+        return;
+    }
+    let irqs_enabled = super::asm::irqs_enabled();
+    super::asm::disable_irqs();
+    unsafe {
+        // enter the no-fill cache mode (CD = 1, NW = 0)
+        let cr0 = cr0();
+        cr0_write((cr0 | Cr0::CR0_CACHE_DISABLE) - Cr0::CR0_NOT_WRITE_THROUGH);
+        core::arch::asm!("wbinvd");
+        super::asm::flush_tlb_global();
+        x86::msr::wrmsr(x86::msr::IA32_PAT, MemoryType::PAT_VALUE);
+        core::arch::asm!("wbinvd");
+        super::asm::flush_tlb_global();
+        cr0_write(cr0);
+    }
+    if irqs_enabled {
+        super::asm::enable_irqs();
+    }
+}
+
+/// Enables the paging features supported by the current CPU, and switches to
+/// the given page table.
+///
+/// It sets `IA32_EFER.NXE` if the execute-disable bit is supported (see
+/// [`CpuFeatures::nx`]), and `CR4.PGE` to enable global pages. Then it writes
+/// the page table root to `CR3`. It decodes the features by `CPUID` rather than
+/// using the per-CPU data, so it can be called before [`init_percpu`].
+///
+/// [`CpuFeatures::nx`]: crate::features::CpuFeatures::nx
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the address translation configuration.
+/// The page table must map the currently running code, and only use the paging
+/// features supported by the CPU, i.e., the execute-disable bit if
+/// [`CpuFeatures::nx`] is set, and pages up to
+/// [`CpuFeatures::max_page_size`]. Otherwise, accesses through the entries
+/// cause page faults with reserved bits set.
+///
+/// [`CpuFeatures::max_page_size`]: crate::features::CpuFeatures::max_page_size
+pub unsafe fn init_mmu(root_paddr: memory_addr::PhysAddr) {
+    use x86::controlregs::{cr3_write, cr4, cr4_write, Cr4};
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+    unsafe {
+        if super::features::CpuFeatures::detect().nx {
+            Efer::update(|efer| *efer |= EferFlags::NO_EXECUTE_ENABLE);
+        }
+        cr4_write(cr4() | Cr4::CR4_ENABLE_GLOBAL_PAGES);
+        cr3_write(root_paddr.as_usize() as _);
+    }
+}
+
+/// Enables the `RDFSBASE`, `WRFSBASE`, `RDGSBASE` and `WRGSBASE` instructions
+/// on the current CPU (`CR4.FSGSBASE`), if supported.
+///
+/// Afterwards, they are used by [`read_thread_pointer`], [`write_thread_pointer`]
+/// and context switches instead of the slower MSR accesses. User space can
+/// also use them directly. It is called by [`init_trap`].
+///
+/// The enabled state is cached in the per-CPU data area, which must have been
+/// set up on the current CPU.
+///
+/// User space can set any GS base with `WRGSBASE`, so the kernel cannot tell
+/// whether the GS base is the per-CPU one by its value. The entry code does
+/// not rely on it: it decides whether to execute `SWAPGS` by the privilege
+/// level of the interrupted code, and the exceptions that may interrupt the
+/// entry and exit code (NMIs, `#MC`, `#DB` and `#DF`) load the per-CPU base
+/// from their IST stacks.
+///
+/// [`read_thread_pointer`]: crate::asm::read_thread_pointer
+/// [`write_thread_pointer`]: crate::asm::write_thread_pointer
+pub fn init_fsgsbase() {
+    use x86::controlregs::{cr4, cr4_write, Cr4};
+    if super::features::cpu_features().fsgsbase {
+        unsafe {
+            cr4_write(cr4() | Cr4::CR4_ENABLE_FSGSBASE);
+            super::asm::FSGSBASE_ENABLED.write_current_raw(true);
+        }
+    }
 }
 
 /// Initializes trap handling on the current CPU.
 ///
-/// In detail, it initializes the GDT, IDT on x86_64 platforms ([`init_gdt`] and
-/// [`init_idt`]). If the `uspace` feature is enabled, it also initializes
+/// In detail, it decodes the features of the current CPU if not done by
+/// [`init_percpu`], initializes the GDT, IDT on x86_64 platforms ([`init_gdt`]
+/// and [`init_idt`]), and enables the `FSGSBASE` instructions if supported
+/// ([`init_fsgsbase`]). If the `uspace` feature is enabled, it also initializes
 /// relevant model-specific registers to configure the handler for `syscall`
-/// instruction ([`init_syscall`]).
+/// instruction ([`init_syscall`]). If the `kpti` feature is enabled, it also
+/// initializes kernel page-table isolation ([`init_kpti`]). If the `apic`
+/// feature is enabled, it also initializes the local APIC
+/// ([`init_local_apic`]). If the `tlb-shootdown` feature is enabled, it also
+/// marks the current CPU as online ([`mark_current_cpu_online`]), so that it
+/// receives TLB shootdowns. The [`CURRENT_CPU_ID`] hook must be registered in
+/// that case.
+///
+/// If the `fp-simd` feature is enabled, it panics if `FXSAVE`/`FXRSTOR`
+/// are not supported (see [`CpuFeatures::fxsr`]).
 ///
 /// # Notes
 /// Before calling this function, the initialization function of the [`percpu`] crate
@@ -30,9 +153,25 @@ pub fn init_percpu(cpu_id: usize) {
 /// correctly (i.e., by calling [`init_percpu`]).
 ///
 /// [`percpu`]: https://docs.rs/percpu/latest/percpu/index.html
+/// [`CpuFeatures::fxsr`]: crate::features::CpuFeatures::fxsr
+/// [`mark_current_cpu_online`]: crate::tlb::mark_current_cpu_online
+/// [`CURRENT_CPU_ID`]: crate::tlb::CURRENT_CPU_ID
 pub fn init_trap() {
+    super::features::init_percpu_features();
+    #[cfg(feature = "fp-simd")]
+    assert!(
+        super::features::cpu_features().fxsr,
+        "FXSAVE/FXRSTOR is not supported"
+    );
     init_gdt();
     init_idt();
+    init_fsgsbase();
     #[cfg(feature = "uspace")]
     init_syscall();
+    #[cfg(feature = "kpti")]
+    init_kpti();
+    #[cfg(feature = "apic")]
+    init_local_apic();
+    #[cfg(feature = "tlb-shootdown")]
+    crate::tlb::mark_current_cpu_online();
 }
@@ -0,0 +1,177 @@
+//! Kernel page-table isolation (KPTI).
+//!
+//! When the `kpti` feature is enabled, user space can run with a separate
+//! page table (the *user page table*), which maps the user memory and only a
+//! few kernel pages required by the entry code:
+//!
+//! - the entry code, see [`entry_text_ranges`];
+//! - the IDT, see [`idt_range`];
+//! - the entry area of each CPU, which contains the GDT, the TSS, the `CR3`
+//!   values to switch to, the entry stack and the IST stacks, see
+//!   [`entry_area_range`]. Other per-CPU data is not exposed.
+//!
+//! On syscalls, interrupts and exceptions from user space, the entry code
+//! switches `CR3` to the kernel page table first, and then moves the trap
+//! frame from the entry stack to the kernel stack. Before returning to user
+//! space, it switches back to the user page table.
+//!
+//! NMIs, machine checks, debug exceptions and double faults may occur at any
+//! point of the entry and exit code, so they are handled by a paranoid entry
+//! on their IST stacks, which always switches to the kernel page table and
+//! the kernel `GS` base, and restores the interrupted ones on return.
+//!
+//! Other IST stacks are not mapped in the user page table, so vectors cannot
+//! be configured to use them (see [`VectorConfig::ist_index`]).
+//!
+//! [`VectorConfig::ist_index`]: crate::vector::VectorConfig::ist_index
+//!
+//! If PCIDs are enabled (`CR4.PCIDE`), the user page table uses the PCID of
+//! the kernel page table with [`USER_PCID_BIT`] set, so that switching between
+//! them does not flush the TLB. In this case, the PCIDs used by the kernel
+//! must be less than [`USER_PCID_BIT`].
+//!
+//! The user page table is set by [`TaskContext::set_user_page_table_root`]
+//! or [`set_user_page_table`]. Before that, user space runs with the kernel
+//! page table as before.
+//!
+//! [`TaskContext::set_user_page_table_root`]: crate::TaskContext::set_user_page_table_root
+
+use core::ops::Range;
+
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+use x86::controlregs;
+use x86_64::instructions::tlb::{self, InvPcidCommand, Pcid};
+
+use super::asm::pcids_enabled;
+use super::features::cpu_features;
+
+/// The bit set in the PCID of the user page table, if PCIDs are enabled.
+pub const USER_PCID_BIT: usize = 0x800;
+
+/// Bit 63 of `CR3`: do not flush the TLB entries of the new PCID.
+const CR3_NOFLUSH: usize = 1 << 63;
+
+/// Initializes KPTI on the current CPU.
+///
+/// It sets `RSP0` of the TSS to the per-CPU entry stack, and uses the current
+/// page table for both kernel and user space. It is called by
+/// [`init_trap`](crate::init::init_trap).
+pub fn init_kpti() {
+    unsafe {
+        super::gdt::write_tss_rsp0_raw(super::gdt::entry_stack_top());
+        set_kernel_page_table(controlregs::cr3() as usize);
+    }
+}
+
+/// Returns the address ranges of the entry code, which must be mapped in the
+/// user page tables.
+pub fn entry_text_ranges() -> [Range<VirtAddr>; 2] {
+    unsafe extern "C" {
+        fn syscall_entry();
+        fn syscall_entry_end();
+        fn trap_entry_start();
+        fn trap_entry_end();
+    }
+    [
+        va!(syscall_entry as *const () as usize)..va!(syscall_entry_end as *const () as usize),
+        va!(trap_entry_start as *const () as usize)..va!(trap_entry_end as *const () as usize),
+    ]
+}
+
+/// Returns the address range of the IDT, which must be mapped in the user
+/// page tables.
+///
+/// # Panics
+///
+/// Panics if the IDT is not initialized by [`init_idt`](crate::init::init_idt).
+pub fn idt_range() -> Range<VirtAddr> {
+    let start = super::idt::IDT.get().expect("IDT is not initialized") as *const _ as usize;
+    va!(start)..va!(start + core::mem::size_of::<super::IdtStruct>())
+}
+
+/// Returns the address range of the entry area of the given CPU, which must be
+/// mapped in the user page tables.
+///
+/// The range is page-aligned, and contains no other per-CPU data.
+///
+/// # Panics
+///
+/// Panics if the per-CPU data areas are not initialized.
+pub fn entry_area_range(cpu_id: usize) -> Range<VirtAddr> {
+    assert!(
+        cpu_id < percpu::percpu_area_num(),
+        "invalid CPU ID: {cpu_id}"
+    );
+    let range = super::gdt::entry_area_range(cpu_id);
+    range.start.align_down_4k()..range.end.align_up_4k()
+}
+
+/// Sets the page table root used in user space on the current CPU.
+///
+/// If PCIDs are enabled, the TLB entries of the user PCID are flushed when the
+/// root changes.
+///
+/// # Safety
+///
+/// The page table must map the user memory, the entry code, the IDT, and the
+/// entry areas of all CPUs in the same way as the current kernel page table.
+pub unsafe fn set_user_page_table(root_paddr: PhysAddr) {
+    let area = super::gdt::entry_area();
+    let kernel_cr3 = unsafe { (*area).kernel_cr3 } & !CR3_NOFLUSH;
+    let root = root_paddr.as_usize() & !0xfff;
+    let user_cr3 = if !pcids_enabled() {
+        root
+    } else if root == kernel_cr3 & !0xfff {
+        kernel_cr3 | CR3_NOFLUSH
+    } else {
+        let pcid = kernel_cr3 & 0xfff;
+        assert!(pcid < USER_PCID_BIT, "PCID {pcid:#x} conflicts with KPTI");
+        let user_cr3 = root | pcid | USER_PCID_BIT;
+        if unsafe { (*area).user_cr3 } & !CR3_NOFLUSH == user_cr3 {
+            return;
+        }
+        if cpu_features().invpcid {
+            let cmd = InvPcidCommand::Single(Pcid::new((pcid | USER_PCID_BIT) as u16).unwrap());
+            unsafe { tlb::flush_pcid(cmd) };
+            user_cr3 | CR3_NOFLUSH
+        } else {
+            // Without `INVPCID`, the user PCID is flushed on each return to
+            // user space.
+            user_cr3
+        }
+    };
+    unsafe { (*area).user_cr3 = user_cr3 };
+}
+
+/// Sets the page table root used in kernel space on the current CPU.
+///
+/// The user page table is reset to the same one, i.e., without isolation.
+pub(super) fn set_kernel_page_table(cr3: usize) {
+    let cr3 = if pcids_enabled() {
+        cr3 | CR3_NOFLUSH
+    } else {
+        cr3
+    };
+    let area = super::gdt::entry_area();
+    unsafe {
+        (*area).kernel_cr3 = cr3;
+        (*area).user_cr3 = cr3;
+    }
+}
+
+/// Flushes the TLB entries of the user PCID corresponding to the given kernel
+/// PCID.
+///
+/// It does nothing if the user PCID is flushed on each return to user space
+/// anyway, i.e., PCIDs are disabled or `INVPCID` is not supported.
+pub(super) fn flush_user_tlb(pcid: usize, vaddr: Option<usize>) {
+    if pcid >= USER_PCID_BIT || !pcids_enabled() || !cpu_features().invpcid {
+        return;
+    }
+    let pcid = Pcid::new((pcid | USER_PCID_BIT) as u16).unwrap();
+    let cmd = match vaddr {
+        Some(vaddr) => InvPcidCommand::Address(x86_64::VirtAddr::new_truncate(vaddr as u64), pcid),
+        None => InvPcidCommand::Single(pcid),
+    };
+    unsafe { tlb::flush_pcid(cmd) }
+}
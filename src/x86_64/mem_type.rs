@@ -0,0 +1,109 @@
+use x86_64::structures::paging::PageTableFlags as PTF;
+
+use crate::mem_type::MemoryType;
+
+impl MemoryType {
+    /// The `IA32_PAT` MSR should be set to this value to match the attribute
+    /// bits returned by [`MemoryType::pte_bits`].
+    ///
+    /// The entries 0 to 3 are the same as the power-on default, i.e., WB, WT,
+    /// UC- and UC. The entry 4 is WC.
+    pub const PAT_VALUE: u64 = {
+        const UC: u64 = 0x00;
+        const WC: u64 = 0x01;
+        const WT: u64 = 0x04;
+        const WP: u64 = 0x05;
+        const WB: u64 = 0x06;
+        const UC_MINUS: u64 = 0x07;
+        WB | (WT << 8)
+            | (UC_MINUS << 16)
+            | (UC << 24)
+            | (WC << 32)
+            | (WP << 40)
+            | (UC_MINUS << 48)
+            | (UC << 56)
+    };
+
+    /// Returns the attribute bits (`PWT`, `PCD` and `PAT`) of 4K page table
+    /// entries.
+    pub const fn pte_bits(self) -> u64 {
+        let index = self.pat_index();
+        (index & 0b011) << 3 | (index & 0b100) << 5 // PAT is bit 7
+    }
+
+    /// Returns the attribute bits (`PWT`, `PCD` and `PAT`) of huge page (2M
+    /// or 1G) entries.
+    pub const fn huge_pte_bits(self) -> u64 {
+        let index = self.pat_index();
+        (index & 0b011) << 3 | (index & 0b100) << 10 // PAT is bit 12
+    }
+
+    /// Returns the page table flags of 4K page table entries.
+    pub const fn pte_flags(self) -> PTF {
+        PTF::from_bits_truncate(self.pte_bits())
+    }
+
+    const fn pat_index(self) -> u64 {
+        match self {
+            Self::WriteBack => 0,
+            Self::WriteThrough => 1,
+            Self::WriteCombining => 4,
+            Self::Uncached | Self::Device | Self::StronglyOrdered => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryType;
+
+    const ALL_TYPES: [MemoryType; 6] = [
+        MemoryType::WriteBack,
+        MemoryType::WriteThrough,
+        MemoryType::WriteCombining,
+        MemoryType::Uncached,
+        MemoryType::Device,
+        MemoryType::StronglyOrdered,
+    ];
+
+    /// Returns the PAT memory type encoding selected by the given index.
+    fn pat_entry(index: u64) -> u64 {
+        (MemoryType::PAT_VALUE >> (index * 8)) & 0xff
+    }
+
+    fn expected_pat_entry(ty: MemoryType) -> u64 {
+        match ty {
+            MemoryType::WriteBack => 0x06,
+            MemoryType::WriteThrough => 0x04,
+            MemoryType::WriteCombining => 0x01,
+            MemoryType::Uncached | MemoryType::Device | MemoryType::StronglyOrdered => 0x00,
+        }
+    }
+
+    #[test]
+    fn pte_bits_select_pat_entries() {
+        for ty in ALL_TYPES {
+            let bits = ty.pte_bits();
+            assert_eq!(bits & !0x98, 0, "{ty:?}: {bits:#x}");
+            let index = (bits >> 3) & 0b11 | (bits >> 5) & 0b100;
+            assert_eq!(pat_entry(index), expected_pat_entry(ty), "{ty:?}");
+        }
+    }
+
+    #[test]
+    fn huge_pte_bits_select_pat_entries() {
+        for ty in ALL_TYPES {
+            let bits = ty.huge_pte_bits();
+            assert_eq!(bits & !0x1018, 0, "{ty:?}: {bits:#x}");
+            let index = (bits >> 3) & 0b11 | (bits >> 10) & 0b100;
+            assert_eq!(pat_entry(index), expected_pat_entry(ty), "{ty:?}");
+        }
+    }
+
+    #[test]
+    fn pat_value_keeps_power_on_defaults() {
+        // WB, WT, UC- and UC
+        assert_eq!(MemoryType::PAT_VALUE & 0xffff_ffff, 0x0007_0406);
+        assert_eq!(MemoryType::WriteBack.pte_bits(), 0);
+    }
+}
@@ -1,9 +1,19 @@
 mod context;
 mod gdt;
 mod idt;
+mod mem_type;
 
 pub mod asm;
+pub mod features;
 pub mod init;
+pub(crate) mod time;
+pub mod vector;
+
+#[cfg(feature = "apic")]
+pub mod apic;
+
+#[cfg(feature = "apic")]
+pub mod smp;
 
 #[cfg(target_os = "none")]
 mod trap;
@@ -14,7 +24,18 @@ mod syscall;
 #[cfg(feature = "uspace")]
 pub mod uspace;
 
-pub use self::context::{ExtendedState, FxsaveArea, TaskContext, TrapFrame};
+#[cfg(feature = "uspace")]
+mod uaccess;
+
+#[cfg(feature = "uspace")]
+pub mod vdso;
+
+#[cfg(feature = "kpti")]
+pub mod kpti;
+
+pub use self::context::{
+    ExtendedState, FiberContext, FpControlState, FxsaveArea, TaskContext, TrapFrame,
+};
 pub use self::gdt::GdtStruct;
 pub use self::idt::IdtStruct;
 pub use x86_64::structures::tss::TaskStateSegment;
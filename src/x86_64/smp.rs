@@ -0,0 +1,143 @@
+//! Helpers to start application processors (APs).
+//!
+//! The startup code (trampoline) is copied to a page below 1 MiB set by
+//! [`init_smp`], and the AP is woken up by the INIT-SIPI-SIPI sequence. It
+//! switches from real mode to long mode directly, with the same page table,
+//! `CR0`, `CR4` (except `PCIDE`) and `IA32_EFER` as the current CPU, and then
+//! jumps to the given entry on the given stack.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use lazyinit::LazyInit;
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+use x86::{controlregs, msr};
+
+#[repr(C)]
+struct ApTrampolineParams {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    stack_top: u64,
+    entry: u64,
+    arg0: u64,
+    arg1: u64,
+}
+
+core::arch::global_asm!(
+    include_str!("ap_start.S"),
+    cr0_offset = const core::mem::offset_of!(ApTrampolineParams, cr0),
+    cr3_offset = const core::mem::offset_of!(ApTrampolineParams, cr3),
+    cr4_offset = const core::mem::offset_of!(ApTrampolineParams, cr4),
+    efer_offset = const core::mem::offset_of!(ApTrampolineParams, efer),
+    stack_top_offset = const core::mem::offset_of!(ApTrampolineParams, stack_top),
+    entry_offset = const core::mem::offset_of!(ApTrampolineParams, entry),
+    arg0_offset = const core::mem::offset_of!(ApTrampolineParams, arg0),
+    arg1_offset = const core::mem::offset_of!(ApTrampolineParams, arg1),
+    params_size = const core::mem::size_of::<ApTrampolineParams>(),
+);
+
+struct SmpConfig {
+    trampoline_paddr: PhysAddr,
+    trampoline_vaddr: VirtAddr,
+    delay_us: fn(u64),
+}
+
+static SMP_CONFIG: LazyInit<SmpConfig> = LazyInit::new();
+
+/// Whether the AP being started has entered Rust code.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the page used by the AP startup code, and the function to wait for
+/// the given microseconds, which are used by [`start_secondary_cpu`].
+///
+/// # Panics
+///
+/// Panics if the page is not 4K-aligned or not below 1 MiB.
+pub fn init_smp(trampoline_paddr: PhysAddr, trampoline_vaddr: VirtAddr, delay_us: fn(u64)) {
+    assert!(
+        trampoline_paddr.is_aligned_4k() && trampoline_paddr.as_usize() < 0x10_0000,
+        "invalid AP trampoline page: {trampoline_paddr:#x}"
+    );
+    SMP_CONFIG.init_once(SmpConfig {
+        trampoline_paddr,
+        trampoline_vaddr,
+        delay_us,
+    });
+}
+
+/// The Rust entry of APs, called by the startup code.
+extern "C" fn ap_entry(cpu_id: usize, entry: usize) -> ! {
+    percpu::init_percpu_reg(cpu_id);
+    AP_STARTED.store(true, Ordering::Release);
+    let entry: fn(usize) -> ! = unsafe { core::mem::transmute(entry) };
+    entry(cpu_id)
+}
+
+/// Starts the application processor with the given local APIC ID.
+///
+/// The AP initializes its per-CPU register for `cpu_id` (the [`percpu`] area
+/// must have been initialized, see [`init_percpu`]), and then calls
+/// `entry(cpu_id)` on the stack `stack_top`. The entry function should call
+/// [`init_trap`] and other per-CPU initialization.
+///
+/// Returns whether the AP has entered Rust code within 1 second.
+///
+/// # Safety
+///
+/// - [`init_smp`] must have been called.
+/// - The trampoline page must be identity-mapped in the current page table,
+///   whose root must be below 4 GiB.
+/// - The stack must be valid, and the target CPU must not be running.
+/// - It must not be called on multiple CPUs concurrently.
+///
+/// [`percpu`]: https://docs.rs/percpu/latest/percpu/index.html
+/// [`init_percpu`]: crate::init::init_percpu
+/// [`init_trap`]: crate::init::init_trap
+pub unsafe fn start_secondary_cpu(
+    cpu_id: usize,
+    apic_id: u32,
+    entry: fn(usize) -> !,
+    stack_top: VirtAddr,
+) -> bool {
+    unsafe extern "C" {
+        fn ap_trampoline_start();
+        fn ap_trampoline_params();
+        fn ap_trampoline_end();
+    }
+    const EFER_LMA: u64 = 1 << 10;
+
+    let config = SMP_CONFIG.get().expect("SMP is not initialized");
+    let start = ap_trampoline_start as *const () as usize;
+    let params_offset = ap_trampoline_params as *const () as usize - start;
+    let size = ap_trampoline_end as *const () as usize - start;
+
+    let cr3 = unsafe { controlregs::cr3() } & 0x000f_ffff_ffff_f000;
+    assert!(cr3 < 1 << 32, "page table root {cr3:#x} is above 4 GiB");
+    let params = ApTrampolineParams {
+        cr0: unsafe { controlregs::cr0() }.bits() as u64,
+        cr3,
+        cr4: (unsafe { controlregs::cr4() } - controlregs::Cr4::CR4_ENABLE_PCID).bits() as u64,
+        efer: unsafe { msr::rdmsr(msr::IA32_EFER) } & !EFER_LMA,
+        stack_top: stack_top.as_usize() as u64,
+        entry: ap_entry as *const () as usize as u64,
+        arg0: cpu_id as u64,
+        arg1: entry as *const () as usize as u64,
+    };
+    unsafe {
+        let dst = config.trampoline_vaddr.as_mut_ptr();
+        core::ptr::copy_nonoverlapping(start as *const u8, dst, size);
+        (dst.add(params_offset) as *mut ApTrampolineParams).write_volatile(params);
+    }
+
+    AP_STARTED.store(false, Ordering::Release);
+    let start_page = (config.trampoline_paddr.as_usize() >> 12) as u8;
+    unsafe { super::apic::start_ap(apic_id, start_page, config.delay_us) };
+    for _ in 0..1000 {
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+        (config.delay_us)(1000);
+    }
+    false
+}
@@ -1,31 +1,82 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86::msr::{wrmsr, IA32_SYSENTER_CS, IA32_SYSENTER_EIP, IA32_SYSENTER_ESP};
 use x86_64::addr::VirtAddr;
 use x86_64::registers::model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star};
 use x86_64::registers::rflags::RFlags;
-use x86_64::structures::tss::TaskStateSegment;
 
+use super::gdt::entry_offsets;
 use super::{GdtStruct, TrapFrame};
 
-#[unsafe(no_mangle)]
-#[percpu::def_percpu]
-static USER_RSP_OFFSET: usize = 0;
-
 core::arch::global_asm!(
     include_str!("syscall.S"),
-    tss_rsp0_offset = const core::mem::offset_of!(TaskStateSegment, privilege_stack_table),
+    tss_rsp0 = const entry_offsets::TSS_RSP0,
+    kernel_cr3 = const entry_offsets::KERNEL_CR3,
+    user_cr3 = const entry_offsets::USER_CR3,
+    kernel_rsp0 = const entry_offsets::KERNEL_RSP0,
+    user_rsp = const entry_offsets::USER_RSP,
+    kpti = const cfg!(feature = "kpti") as u8,
+    ucode32_selector = const GdtStruct::UCODE32_SELECTOR.0,
+    udata_selector = const GdtStruct::UDATA_SELECTOR.0,
 );
 
+/// The user address to return to after `sysenter`, set by [`init_sysenter`].
+static SYSENTER_RETURN: AtomicU64 = AtomicU64::new(0);
+
 #[unsafe(no_mangle)]
 pub(super) fn x86_syscall_handler(tf: &mut TrapFrame) {
     tf.rax = crate::trap::handle_syscall(tf, tf.rax as usize) as u64;
 }
 
+/// Handles syscalls from the `sysenter` instruction.
+///
+/// Returns whether the `sysexit` instruction can be used to return, i.e., the
+/// trap frame still returns to the `sysenter` stub, and `RFLAGS` has no other
+/// bits than the status flags and `IF`. The entry code restores the status
+/// flags with `POPFQ` before `sysexit`, so other flags (e.g., `TF` and `AC`)
+/// must be restored by `iretq` to not take effect in the kernel.
+#[unsafe(no_mangle)]
+extern "C" fn x86_sysenter_handler(tf: &mut TrapFrame) -> bool {
+    // CF, bit 1 (always set), PF, AF, ZF, SF, IF and OF
+    const SYSEXIT_RFLAGS: u64 = 0xad7;
+    let ret = SYSENTER_RETURN.load(Ordering::Relaxed);
+    tf.rip = ret;
+    x86_syscall_handler(tf);
+    tf.rip == ret
+        && tf.cs == GdtStruct::UCODE32_SELECTOR.0 as u64
+        && tf.rflags & !SYSEXIT_RFLAGS == 0
+}
+
+/// Handles the debug exception (`#DB`) raised by single-stepping the
+/// `sysenter` entry code.
+///
+/// `sysenter` does not clear `RFLAGS.TF`, so if user space executes it with
+/// `TF` set, a single-step `#DB` is raised in the kernel before the entry code
+/// clears the flags. In that case, `TF` is cleared in the trap frame to resume
+/// the entry code, and returns `true`. The single step of user space is lost,
+/// i.e., `TF` is clear after returning to user space.
+#[cfg(target_os = "none")]
+pub(super) fn handle_sysenter_single_step(tf: &mut TrapFrame) -> bool {
+    unsafe extern "C" {
+        fn sysenter_entry();
+        fn sysenter_flags_cleared();
+    }
+    let start = sysenter_entry as *const () as u64;
+    let end = sysenter_flags_cleared as *const () as u64;
+    if tf.is_user() || !(start..=end).contains(&tf.rip) {
+        return false;
+    }
+    tf.rflags &= !RFlags::TRAP_FLAG.bits();
+    true
+}
+
 /// Initializes syscall support and setups the syscall handler.
 pub fn init_syscall() {
     unsafe extern "C" {
         fn syscall_entry();
     }
     unsafe {
-        LStar::write(VirtAddr::new(syscall_entry as usize as _));
+        LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
         Star::write(
             GdtStruct::UCODE64_SELECTOR,
             GdtStruct::UDATA_SELECTOR,
@@ -46,3 +97,41 @@ pub fn init_syscall() {
         KernelGsBase::write(VirtAddr::new(0));
     }
 }
+
+/// Initializes the `sysenter` instruction for compatibility mode (32-bit)
+/// processes on the current CPU.
+///
+/// `sysenter` does not save the user `EIP` and `ESP`, so user space must enter
+/// the kernel via the stub in the vDSO page (see [`vdso`]), which is mapped at
+/// `vdso_base` in all processes:
+///
+/// - the syscall number and the first 5 arguments are passed in `EAX`, `EBX`,
+///   `ECX`, `EDX`, `ESI` and `EDI` as with `int 0x80`;
+/// - the user stack pointer is passed in `EBP`, with the 6th argument at the
+///   top of the user stack.
+///
+/// The syscalls are handled by the [`SYSCALL`] handlers, with `CS` in the trap
+/// frame set to the 32-bit user code segment. If the handler does not change
+/// the return address and `CS`, it returns to the stub by `sysexit`, otherwise
+/// by `iretq`.
+///
+/// `sysenter` is only supported in compatibility mode by Intel CPUs.
+///
+/// [`vdso`]: crate::vdso
+/// [`SYSCALL`]: crate::trap::SYSCALL
+pub fn init_sysenter(vdso_base: memory_addr::VirtAddr) {
+    unsafe extern "C" {
+        fn sysenter_entry();
+    }
+    let ret = vdso_base.as_usize() + super::vdso::sysenter_return_offset();
+    SYSENTER_RETURN.store(ret as u64, Ordering::Relaxed);
+    unsafe {
+        // `SS` is the next selector (`KDATA_SELECTOR`), and `SYSEXIT` uses the
+        // 32-bit user selectors that follow (`UCODE32_SELECTOR` and
+        // `UDATA_SELECTOR`).
+        wrmsr(IA32_SYSENTER_CS, GdtStruct::KCODE64_SELECTOR.0 as u64);
+        // The kernel stack is loaded from the TSS by the entry code.
+        wrmsr(IA32_SYSENTER_ESP, 0);
+        wrmsr(IA32_SYSENTER_EIP, sysenter_entry as *const () as u64);
+    }
+}
@@ -0,0 +1,170 @@
+//! Cycle counter based on the time-stamp counter (TSC).
+//!
+//! The TSC is a reliable monotonic clock source only if it is invariant, i.e.,
+//! runs at a constant rate in all ACPI P-, C- and T-states (see
+//! [`has_invariant_tsc`]).
+
+use memory_addr::VirtAddr;
+
+use super::features::cpu_features;
+
+/// The time used to calibrate the TSC frequency, in milliseconds.
+const CALIBRATE_MS: u64 = 10;
+
+/// Returns whether the TSC is invariant (`CPUID.80000007H:EDX[8]`).
+#[inline]
+pub fn has_invariant_tsc() -> bool {
+    cpu_features().invariant_tsc
+}
+
+/// Returns the current value of the cycle counter (TSC).
+///
+/// `RDTSCP` is used if supported, which waits until all previous instructions
+/// have executed. Otherwise, `LFENCE` is executed before `RDTSC`.
+#[inline]
+pub fn cycles() -> u64 {
+    if cpu_features().rdtscp {
+        let mut aux = 0;
+        unsafe { core::arch::x86_64::__rdtscp(&mut aux) }
+    } else {
+        unsafe {
+            core::arch::x86_64::_mm_lfence();
+            core::arch::x86_64::_rdtsc()
+        }
+    }
+}
+
+/// Determines the frequency of the TSC from, in order:
+///
+/// - the TSC and core crystal clock information (`CPUID.15H`);
+/// - calibration against the PIT channel 2 (on bare metal only);
+/// - the processor base frequency (`CPUID.16H`).
+pub(crate) fn detect_cycle_frequency() -> Option<u64> {
+    if !has_invariant_tsc() {
+        warn!("TSC is not invariant, the cycle frequency may be inaccurate");
+    }
+    cpuid_tsc_frequency()
+        .or_else(calibrate_with_pit)
+        .or_else(cpuid_base_frequency)
+}
+
+fn cpuid_tsc_frequency() -> Option<u64> {
+    x86::cpuid::CpuId::new()
+        .get_tsc_info()
+        .and_then(|info| info.tsc_frequency())
+}
+
+fn cpuid_base_frequency() -> Option<u64> {
+    x86::cpuid::CpuId::new()
+        .get_processor_frequency_info()
+        .map(|info| info.processor_base_frequency() as u64 * 1_000_000)
+        .filter(|&freq| freq != 0)
+}
+
+/// Calibrates the TSC frequency against the PIT channel 2, which runs at
+/// 1.193182 MHz.
+///
+/// Returns [`None`] if the PIT is absent, i.e., its output is high
+/// immediately, or does not go high after polling for about a second.
+#[cfg(target_os = "none")]
+fn calibrate_with_pit() -> Option<u64> {
+    use x86::io::{inb, outb};
+    const PIT_FREQUENCY: u64 = 1_193_182;
+    const PIT_CH2_DATA: u16 = 0x42;
+    const PIT_COMMAND: u16 = 0x43;
+    const PORT_B: u16 = 0x61;
+    // each read of the I/O port takes about 1 microsecond
+    const MAX_POLLS: u64 = 1_000_000;
+
+    let latch = PIT_FREQUENCY * CALIBRATE_MS / 1000;
+    unsafe {
+        let port_b = inb(PORT_B);
+        // enable the gate of channel 2, and disable the speaker
+        outb(PORT_B, (port_b & !0x02) | 0x01);
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        outb(PIT_COMMAND, 0xb0);
+        outb(PIT_CH2_DATA, latch as u8);
+        outb(PIT_CH2_DATA, (latch >> 8) as u8);
+
+        let start = cycles();
+        let mut polls = 0u64;
+        // the output of channel 2 (bit 5) goes high at terminal count
+        while inb(PORT_B) & 0x20 == 0 && polls < MAX_POLLS {
+            polls += 1;
+        }
+        let end = cycles();
+        outb(PORT_B, port_b);
+
+        if polls == 0 || polls == MAX_POLLS {
+            return None;
+        }
+        Some((end - start) * 1000 / CALIBRATE_MS)
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+fn calibrate_with_pit() -> Option<u64> {
+    None
+}
+
+/// Calibrates the TSC frequency against the HPET main counter, and sets it as
+/// the cycle frequency.
+///
+/// The HPET is enabled if it is not. If the HPET reports an invalid counter
+/// period, or its counter does not advance, it falls back to the sources used
+/// by [`init_cycle_frequency`]. Returns the frequency in Hz, or zero if it
+/// cannot be determined.
+///
+/// [`init_cycle_frequency`]: crate::time::init_cycle_frequency
+///
+/// # Safety
+///
+/// `hpet_base` must be the virtual address of the HPET MMIO registers.
+pub unsafe fn calibrate_with_hpet(hpet_base: VirtAddr) -> u64 {
+    let freq = unsafe { hpet_tsc_frequency(hpet_base) }
+        .or_else(detect_cycle_frequency)
+        .unwrap_or(0);
+    crate::time::set_cycle_frequency(freq);
+    freq
+}
+
+unsafe fn hpet_tsc_frequency(hpet_base: VirtAddr) -> Option<u64> {
+    const CAPABILITIES: usize = 0x00;
+    const CONFIG: usize = 0x10;
+    const MAIN_COUNTER: usize = 0xf0;
+    const CONFIG_ENABLE: u64 = 1;
+    // the maximum counter period allowed by the specification (100 ns)
+    const MAX_PERIOD_FS: u64 = 0x05f5_e100;
+    // each read of the main counter takes about 1 microsecond
+    const MAX_POLLS: u64 = 1_000_000;
+
+    let reg = |offset: usize| (hpet_base.as_usize() + offset) as *mut u64;
+    unsafe {
+        // the counter period in femtoseconds
+        let period = reg(CAPABILITIES).read_volatile() >> 32;
+        if period == 0 || period > MAX_PERIOD_FS {
+            warn!("Invalid HPET counter period: {period} fs");
+            return None;
+        }
+        let config = reg(CONFIG).read_volatile();
+        reg(CONFIG).write_volatile(config | CONFIG_ENABLE);
+
+        let ticks = CALIBRATE_MS * 1_000_000_000_000 / period;
+        let hpet_start = reg(MAIN_COUNTER).read_volatile();
+        let start = cycles();
+        let mut hpet_end = hpet_start;
+        let mut polls = 0u64;
+        while hpet_end.wrapping_sub(hpet_start) < ticks && polls < MAX_POLLS {
+            core::hint::spin_loop();
+            hpet_end = reg(MAIN_COUNTER).read_volatile();
+            polls += 1;
+        }
+        let end = cycles();
+        let elapsed_fs = hpet_end.wrapping_sub(hpet_start) as u128 * period as u128;
+        if elapsed_fs == 0 {
+            warn!("HPET main counter does not advance");
+            return None;
+        }
+        Some(((end - start) as u128 * 1_000_000_000_000_000 / elapsed_fs) as u64)
+    }
+}
@@ -1,10 +1,22 @@
+use core::sync::atomic::Ordering;
+
+use x86::msr::{rdmsr, IA32_MCG_CAP, IA32_MCG_STATUS};
 use x86::{controlregs::cr2, irq::*};
 use x86_64::structures::idt::PageFaultErrorCode;
 
 use super::context::TrapFrame;
+use super::gdt::entry_offsets;
 use crate::trap::PageFaultFlags;
 
-core::arch::global_asm!(include_str!("trap.S"));
+core::arch::global_asm!(
+    include_str!("trap.S"),
+    uspace = const cfg!(feature = "uspace") as u8,
+    kpti = const cfg!(feature = "kpti") as u8,
+    tss_rsp0 = const entry_offsets::TSS_RSP0,
+    kernel_cr3 = const entry_offsets::KERNEL_CR3,
+    user_cr3 = const entry_offsets::USER_CR3,
+    kernel_rsp0 = const entry_offsets::KERNEL_RSP0,
+);
 
 #[cfg(feature = "uspace")]
 const LEGACY_SYSCALL_VECTOR: u8 = 0x80;
@@ -29,25 +41,92 @@ fn handle_page_fault(tf: &TrapFrame) {
     }
 }
 
-#[unsafe(no_mangle)]
-fn x86_trap_handler(tf: &mut TrapFrame) {
-    match tf.vector as u8 {
-        PAGE_FAULT_VECTOR => handle_page_fault(tf),
-        BREAKPOINT_VECTOR => debug!("#BP @ {:#x} ", tf.rip),
-        GENERAL_PROTECTION_FAULT_VECTOR => {
-            panic!(
-                "#GP @ {:#x}, error_code={:#x}:\n{:#x?}",
-                tf.rip, tf.error_code, tf
-            );
+fn handle_double_fault(tf: &TrapFrame) -> ! {
+    // The fault address is useful if the #DF is caused by a #PF on the kernel
+    // stack, e.g., a stack overflow.
+    panic!(
+        "#DF @ {:#x}, rsp={:#x}, cr2={:#x}:\n{:#x?}",
+        tf.rip,
+        tf.rsp,
+        unsafe { cr2() },
+        tf
+    );
+}
+
+fn handle_nmi(tf: &TrapFrame) {
+    // Call all handlers, since multiple sources may raise NMIs at the same time
+    // and they are collapsed into one if NMIs are blocked.
+    let handled = crate::trap::NMI
+        .iter()
+        .fold(false, |handled, f| f(tf) | handled);
+    if handled {
+        return;
+    }
+    // Bits 7 and 6 of the system control port B indicate memory parity (SERR#)
+    // and I/O channel check (IOCHK#) errors on legacy platforms. Other NMIs are
+    // counted without logging, since an NMI may interrupt the logger itself.
+    const NMI_REASON_SERR: u8 = 1 << 7;
+    const NMI_REASON_IOCHK: u8 = 1 << 6;
+    let reason: u8 = unsafe { x86::io::inb(0x61) };
+    let error = match reason & (NMI_REASON_SERR | NMI_REASON_IOCHK) {
+        0 => {
+            crate::trap::UNKNOWN_NMI_COUNT.fetch_add(1, Ordering::Relaxed);
+            return;
         }
-        #[cfg(feature = "uspace")]
-        LEGACY_SYSCALL_VECTOR => super::syscall::x86_syscall_handler(tf),
-        IRQ_VECTOR_START..=IRQ_VECTOR_END => {
-            handle_trap!(IRQ, tf.vector as _);
+        NMI_REASON_SERR => "memory parity error",
+        NMI_REASON_IOCHK => "I/O channel check error",
+        _ => "memory parity and I/O channel check errors",
+    };
+    panic!(
+        "NMI @ {:#x} ({} mode), {}, port 0x61={:#x}:\n{:#x?}",
+        tf.rip,
+        if tf.is_user() { "user" } else { "kernel" },
+        error,
+        reason,
+        tf
+    );
+}
+
+fn handle_machine_check(tf: &TrapFrame) -> ! {
+    const MCI_STATUS_VAL: u64 = 1 << 63;
+    const MCI_STATUS_ADDRV: u64 = 1 << 58;
+    let (mcg_cap, mcg_status) = unsafe { (rdmsr(IA32_MCG_CAP), rdmsr(IA32_MCG_STATUS)) };
+    error!(
+        "#MC @ {:#x}, MCG_CAP={:#x}, MCG_STATUS={:#x}",
+        tf.rip, mcg_cap, mcg_status
+    );
+    for bank in 0..(mcg_cap & 0xff) as u32 {
+        // IA32_MCi_STATUS and IA32_MCi_ADDR
+        let status = unsafe { rdmsr(0x401 + 4 * bank) };
+        if status & MCI_STATUS_VAL != 0 {
+            let addr = if status & MCI_STATUS_ADDRV != 0 {
+                unsafe { rdmsr(0x402 + 4 * bank) }
+            } else {
+                0
+            };
+            error!("  bank {}: status={:#x}, addr={:#x}", bank, status, addr);
+        }
+    }
+    panic!("Unrecoverable #MC @ {:#x}:\n{:#x?}", tf.rip, tf);
+}
+
+fn handle_exception(tf: &mut TrapFrame) {
+    let vector = tf.vector as u8;
+    // #BP does not require a handler, so skip the warning of missing handlers
+    let call_handlers = vector != BREAKPOINT_VECTOR || !crate::trap::EXCEPTION.is_empty();
+    if call_handlers && handle_trap!(EXCEPTION, tf, vector, tf.error_code) {
+        return;
+    }
+    match vector {
+        BREAKPOINT_VECTOR => debug!("#BP @ {:#x} ", tf.rip),
+        DEBUG_VECTOR => {
+            let dr6 = unsafe { x86::debugregs::dr6() };
+            panic!("Unhandled #DB @ {:#x}, dr6={:?}:\n{:#x?}", tf.rip, dr6, tf);
         }
         _ => {
             panic!(
-                "Unhandled exception {} ({}, error_code={:#x}) @ {:#x}:\n{:#x?}",
+                "Unhandled {} exception {} ({}, error_code={:#x}) @ {:#x}:\n{:#x?}",
+                if tf.is_user() { "user" } else { "kernel" },
                 tf.vector,
                 vec_to_str(tf.vector),
                 tf.error_code,
@@ -58,6 +137,26 @@ fn x86_trap_handler(tf: &mut TrapFrame) {
     }
 }
 
+#[unsafe(no_mangle)]
+fn x86_trap_handler(tf: &mut TrapFrame) {
+    match tf.vector as u8 {
+        PAGE_FAULT_VECTOR => handle_page_fault(tf),
+        DOUBLE_FAULT_VECTOR => handle_double_fault(tf),
+        NONMASKABLE_INTERRUPT_VECTOR => handle_nmi(tf),
+        MACHINE_CHECK_VECTOR => handle_machine_check(tf),
+        #[cfg(feature = "uspace")]
+        DEBUG_VECTOR if super::syscall::handle_sysenter_single_step(tf) => {}
+        0..IRQ_VECTOR_START => handle_exception(tf),
+        #[cfg(feature = "uspace")]
+        LEGACY_SYSCALL_VECTOR => super::syscall::x86_syscall_handler(tf),
+        #[cfg(feature = "apic")]
+        super::apic::SPURIOUS_VECTOR => {}
+        IRQ_VECTOR_START..=IRQ_VECTOR_END => {
+            handle_trap!(IRQ, tf.vector as _);
+        }
+    }
+}
+
 fn vec_to_str(vec: u64) -> &'static str {
     if vec < 32 {
         EXCEPTIONS[vec as usize].mnemonic
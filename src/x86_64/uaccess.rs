@@ -0,0 +1,46 @@
+use x86::controlregs::{self, Cr4};
+use x86_64::registers::rflags::{self, RFlags};
+
+use super::features::cpu_features;
+use crate::uaccess::UserAccessGuard;
+
+/// Enables the protection of user memory on the current CPU.
+///
+/// It sets the following bits in `CR4` if supported:
+///
+/// - `SMEP`: the kernel cannot execute code in user pages;
+/// - `SMAP`: the kernel cannot access user pages, except while `RFLAGS.AC` is
+///   set, i.e., in the scope of a [`UserAccessGuard`];
+/// - `UMIP`: user space cannot execute `SGDT`, `SIDT`, `SLDT`, `SMSW` and
+///   `STR`.
+///
+/// It is opt-in, and should be called after [`init_trap`] on each CPU.
+///
+/// [`init_trap`]: crate::init::init_trap
+pub fn init_uaccess_protection() {
+    let features = cpu_features();
+    let mut flags = Cr4::empty();
+    flags.set(Cr4::CR4_ENABLE_SMEP, features.smep);
+    flags.set(Cr4::CR4_ENABLE_SMAP, features.smap);
+    flags.set(Cr4::CR4_ENABLE_UMIP, features.umip);
+    unsafe { controlregs::cr4_write(controlregs::cr4() | flags) }
+}
+
+impl UserAccessGuard {
+    /// Sets `RFLAGS.AC` (`STAC`) if SMAP is supported. Returns whether it was
+    /// clear before.
+    #[inline]
+    pub(crate) fn allow_user_access() -> bool {
+        let forbidden = cpu_features().smap && !rflags::read().contains(RFlags::ALIGNMENT_CHECK);
+        if forbidden {
+            unsafe { core::arch::asm!("stac", options(nostack)) }
+        }
+        forbidden
+    }
+
+    /// Clears `RFLAGS.AC` (`CLAC`).
+    #[inline]
+    pub(crate) fn forbid_user_access() {
+        unsafe { core::arch::asm!("clac", options(nostack)) }
+    }
+}
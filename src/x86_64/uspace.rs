@@ -29,6 +29,18 @@ impl UspaceContext {
         })
     }
 
+    /// Creates a new context in compatibility mode (32-bit) with the given
+    /// entry point, user stack pointer, and the argument.
+    ///
+    /// The argument is passed in `EDI`, as there is no standard register for
+    /// it.
+    pub fn new_compat(entry: usize, ustack_top: VirtAddr, arg0: usize) -> Self {
+        use crate::GdtStruct;
+        let mut ctx = Self::new(entry, ustack_top, arg0);
+        ctx.0.cs = GdtStruct::UCODE32_SELECTOR.0 as _;
+        ctx
+    }
+
     /// Creates a new context from the given [`TrapFrame`].
     ///
     /// It copies almost all registers except `CS` and `SS` which need to be
@@ -79,6 +91,35 @@ impl UspaceContext {
     pub unsafe fn enter_uspace(&self, kstack_top: VirtAddr) -> ! {
         crate::asm::disable_irqs();
         assert_eq!(super::gdt::read_tss_rsp0(), kstack_top);
+        #[cfg(not(feature = "kpti"))]
+        unsafe {
+            core::arch::asm!("
+                mov     rsp, {tf}
+                pop     rax
+                pop     rcx
+                pop     rdx
+                pop     rbx
+                pop     rbp
+                pop     rsi
+                pop     rdi
+                pop     r8
+                pop     r9
+                pop     r10
+                pop     r11
+                pop     r12
+                pop     r13
+                pop     r14
+                pop     r15
+                add     rsp, 16     // skip vector, error_code
+                swapgs
+                iretq",
+                tf = in(reg) &self.0,
+                options(noreturn),
+            )
+        }
+        // Move the interrupt frame to the entry stack, and switch to the user
+        // page table.
+        #[cfg(feature = "kpti")]
         unsafe {
             core::arch::asm!("
                 mov     rsp, {tf}
@@ -98,9 +139,23 @@ impl UspaceContext {
                 pop     r14
                 pop     r15
                 add     rsp, 16     // skip vector, error_code
+                push    rax
+                mov     rax, rsp
+                mov     rsp, gs:[offset __PERCPU_CPU_ENTRY_AREA + {tss_rsp0}]
+                push    qword ptr [rax + 5 * 8]     // ss
+                push    qword ptr [rax + 4 * 8]     // rsp
+                push    qword ptr [rax + 3 * 8]     // rflags
+                push    qword ptr [rax + 2 * 8]     // cs
+                push    qword ptr [rax + 1 * 8]     // rip
+                push    qword ptr [rax]             // rax
+                mov     rax, gs:[offset __PERCPU_CPU_ENTRY_AREA + {user_cr3}]
+                mov     cr3, rax
+                pop     rax
                 swapgs
                 iretq",
                 tf = in(reg) &self.0,
+                tss_rsp0 = const super::gdt::entry_offsets::TSS_RSP0,
+                user_cr3 = const super::gdt::entry_offsets::USER_CR3,
                 options(noreturn),
             )
         }
@@ -0,0 +1,287 @@
+//! The vDSO page shared with user space.
+//!
+//! The page is built by [`init_vdso_page`], and should be mapped read-only and
+//! executable in user processes. It contains:
+//!
+//! - the time data ([`VdsoTimeData`]) at [`TIME_DATA_OFFSET`], from which user
+//!   space can implement `gettimeofday` and `clock_gettime` without syscalls;
+//! - the 32-bit `__kernel_vsyscall` stub at [`VSYSCALL_OFFSET`], which enters
+//!   the kernel by `sysenter` (see [`init_sysenter`]).
+//!
+//! [`init_sysenter`]: crate::init::init_sysenter
+
+use core::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+
+use memory_addr::{MemoryAddr, VirtAddr, PAGE_SIZE_4K};
+
+/// The offset of the time data in the vDSO page.
+pub const TIME_DATA_OFFSET: usize = 0;
+
+/// The offset of the `__kernel_vsyscall` stub in the vDSO page.
+pub const VSYSCALL_OFFSET: usize = 0x800;
+
+/// Returns the offset in the vDSO page that `sysexit` returns to, i.e., the
+/// instruction after `sysenter` in the `__kernel_vsyscall` stub.
+pub(super) fn sysenter_return_offset() -> usize {
+    unsafe extern "C" {
+        fn vdso_vsyscall_start();
+        fn vdso_sysenter_return();
+    }
+    // The label difference is computed at run time, since it is not an
+    // absolute expression to the assembler in optimized builds.
+    VSYSCALL_OFFSET + vdso_sysenter_return as *const () as usize
+        - vdso_vsyscall_start as *const () as usize
+}
+
+core::arch::global_asm!(
+    "
+.section .rodata.vdso_vsyscall, \"a\"
+.code32
+.global vdso_vsyscall_start
+vdso_vsyscall_start:
+    push    ecx
+    push    edx
+    push    ebp
+    mov     ebp, esp
+    sysenter
+.global vdso_sysenter_return
+vdso_sysenter_return:
+    pop     ebp
+    pop     edx
+    pop     ecx
+    ret
+.global vdso_vsyscall_end
+vdso_vsyscall_end:
+.code64
+.section .text
+"
+);
+
+/// Time data in the vDSO page, read by user space without syscalls.
+///
+/// The data is protected by a sequence lock: the kernel increases `seq` to an
+/// odd number before updating, and to an even number after that. Readers
+/// retry if `seq` is odd or changes during the read, see [`read`].
+///
+/// The current time is computed from the TSC as
+/// `base + ((rdtsc() - cycle_last) * mult) >> shift` nanoseconds.
+///
+/// [`read`]: VdsoTimeData::read
+#[repr(C, align(64))]
+#[derive(Debug)]
+pub struct VdsoTimeData {
+    /// The sequence counter.
+    pub seq: AtomicU32,
+    /// Non-zero if the TSC can be used by user space. Otherwise, user space
+    /// should fall back to syscalls.
+    pub tsc_enabled: AtomicU32,
+    /// The TSC value at the last update.
+    pub cycle_last: AtomicU64,
+    /// The multiplier to convert TSC cycles to nanoseconds.
+    pub mult: AtomicU32,
+    /// The shift to convert TSC cycles to nanoseconds.
+    pub shift: AtomicU32,
+    /// Seconds of the wall-clock time (`CLOCK_REALTIME`) at the last update.
+    pub realtime_sec: AtomicU64,
+    /// Nanoseconds of the wall-clock time at the last update.
+    pub realtime_nsec: AtomicU64,
+    /// Seconds of the monotonic time (`CLOCK_MONOTONIC`) at the last update.
+    pub monotonic_sec: AtomicU64,
+    /// Nanoseconds of the monotonic time at the last update.
+    pub monotonic_nsec: AtomicU64,
+    /// Minutes west of Greenwich, returned by `gettimeofday`.
+    pub tz_minuteswest: AtomicI32,
+    /// Type of DST correction, returned by `gettimeofday`.
+    pub tz_dsttime: AtomicI32,
+}
+
+/// A consistent snapshot of [`VdsoTimeData`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VdsoTimeSnapshot {
+    /// Whether the TSC can be used by user space.
+    pub tsc_enabled: bool,
+    /// The TSC value at the last update.
+    pub cycle_last: u64,
+    /// The multiplier to convert TSC cycles to nanoseconds.
+    pub mult: u32,
+    /// The shift to convert TSC cycles to nanoseconds.
+    pub shift: u32,
+    /// The wall-clock time at the last update.
+    pub realtime: Duration,
+    /// The monotonic time at the last update.
+    pub monotonic: Duration,
+    /// Minutes west of Greenwich.
+    pub tz_minuteswest: i32,
+    /// Type of DST correction.
+    pub tz_dsttime: i32,
+}
+
+impl VdsoTimeSnapshot {
+    /// Returns the time elapsed from the last update to the given TSC value.
+    ///
+    /// Returns zero if the TSC value is before the last update, and saturates
+    /// at [`u64::MAX`] nanoseconds.
+    pub fn elapsed(&self, tsc: u64) -> Duration {
+        let delta = tsc.saturating_sub(self.cycle_last) as u128;
+        let nanos = (delta * self.mult as u128)
+            .checked_shr(self.shift)
+            .unwrap_or(0);
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+}
+
+impl VdsoTimeData {
+    /// Updates the time data with the given snapshot.
+    ///
+    /// It should not be called concurrently.
+    pub fn update(&self, snapshot: &VdsoTimeSnapshot) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        core::sync::atomic::fence(Ordering::Release);
+        self.tsc_enabled
+            .store(snapshot.tsc_enabled as u32, Ordering::Relaxed);
+        self.cycle_last
+            .store(snapshot.cycle_last, Ordering::Relaxed);
+        self.mult.store(snapshot.mult, Ordering::Relaxed);
+        self.shift.store(snapshot.shift, Ordering::Relaxed);
+        self.realtime_sec
+            .store(snapshot.realtime.as_secs(), Ordering::Relaxed);
+        self.realtime_nsec
+            .store(snapshot.realtime.subsec_nanos() as u64, Ordering::Relaxed);
+        self.monotonic_sec
+            .store(snapshot.monotonic.as_secs(), Ordering::Relaxed);
+        self.monotonic_nsec
+            .store(snapshot.monotonic.subsec_nanos() as u64, Ordering::Relaxed);
+        self.tz_minuteswest
+            .store(snapshot.tz_minuteswest, Ordering::Relaxed);
+        self.tz_dsttime
+            .store(snapshot.tz_dsttime, Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Reads a consistent snapshot of the time data.
+    pub fn read(&self) -> VdsoTimeSnapshot {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if !seq.is_multiple_of(2) {
+                core::hint::spin_loop();
+                continue;
+            }
+            let snapshot = VdsoTimeSnapshot {
+                tsc_enabled: self.tsc_enabled.load(Ordering::Relaxed) != 0,
+                cycle_last: self.cycle_last.load(Ordering::Relaxed),
+                mult: self.mult.load(Ordering::Relaxed),
+                shift: self.shift.load(Ordering::Relaxed),
+                realtime: Duration::new(
+                    self.realtime_sec.load(Ordering::Relaxed),
+                    self.realtime_nsec.load(Ordering::Relaxed) as u32,
+                ),
+                monotonic: Duration::new(
+                    self.monotonic_sec.load(Ordering::Relaxed),
+                    self.monotonic_nsec.load(Ordering::Relaxed) as u32,
+                ),
+                tz_minuteswest: self.tz_minuteswest.load(Ordering::Relaxed),
+                tz_dsttime: self.tz_dsttime.load(Ordering::Relaxed),
+            };
+            core::sync::atomic::fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return snapshot;
+            }
+        }
+    }
+}
+
+/// Builds the vDSO page at the given 4K-aligned kernel virtual address, and
+/// returns the time data in it.
+///
+/// The time data is zeroed with `tsc_enabled` cleared, and should be updated
+/// by [`VdsoTimeData::update`] periodically (e.g., on timer interrupts).
+///
+/// # Safety
+///
+/// The page must be valid for writes, and not used for other purposes.
+pub unsafe fn init_vdso_page(page: VirtAddr) -> &'static VdsoTimeData {
+    unsafe extern "C" {
+        fn vdso_vsyscall_start();
+        fn vdso_vsyscall_end();
+    }
+    assert!(page.is_aligned_4k(), "unaligned vDSO page: {page:#x}");
+    let start = vdso_vsyscall_start as *const () as usize;
+    let size = vdso_vsyscall_end as *const () as usize - start;
+    unsafe {
+        let dst = page.as_mut_ptr();
+        core::ptr::write_bytes(dst, 0, PAGE_SIZE_4K);
+        core::ptr::copy_nonoverlapping(start as *const u8, dst.add(VSYSCALL_OFFSET), size);
+        &*(dst.add(TIME_DATA_OFFSET) as *const VdsoTimeData)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(cycle_last: u64, mult: u32, shift: u32) -> VdsoTimeSnapshot {
+        VdsoTimeSnapshot {
+            cycle_last,
+            mult,
+            shift,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn elapsed() {
+        // 1 GHz
+        let s = snapshot(1000, 1 << 20, 20);
+        assert_eq!(s.elapsed(1000), Duration::ZERO);
+        assert_eq!(s.elapsed(3500), Duration::from_nanos(2500));
+        // 3 GHz, i.e., 1/3 ns per cycle
+        let s = snapshot(0, 0x5555_5555, 32);
+        assert_eq!(s.elapsed(3_000_000_000), Duration::from_nanos(999_999_999));
+    }
+
+    #[test]
+    fn elapsed_before_last_update() {
+        let s = snapshot(1000, 1 << 20, 20);
+        assert_eq!(s.elapsed(999), Duration::ZERO);
+        assert_eq!(s.elapsed(0), Duration::ZERO);
+    }
+
+    #[test]
+    fn elapsed_saturates() {
+        let s = snapshot(0, u32::MAX, 0);
+        assert_eq!(s.elapsed(u64::MAX), Duration::from_nanos(u64::MAX));
+        let s = snapshot(0, u32::MAX, 128);
+        assert_eq!(s.elapsed(u64::MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn update_and_read() {
+        let data: VdsoTimeData = unsafe { core::mem::zeroed() };
+        let s = VdsoTimeSnapshot {
+            tsc_enabled: true,
+            cycle_last: 0x1234_5678_9abc,
+            mult: 0x5555_5555,
+            shift: 32,
+            realtime: Duration::new(1_700_000_000, 123_456_789),
+            monotonic: Duration::new(42, 999_999_999),
+            tz_minuteswest: -480,
+            tz_dsttime: 1,
+        };
+        data.update(&s);
+        assert_eq!(data.seq.load(Ordering::Relaxed), 2);
+        assert_eq!(data.read(), s);
+    }
+
+    #[test]
+    fn sysenter_return_follows_sysenter() {
+        unsafe extern "C" {
+            fn vdso_vsyscall_start();
+        }
+        let offset = sysenter_return_offset() - VSYSCALL_OFFSET;
+        let start = vdso_vsyscall_start as *const () as *const u8;
+        let insn = unsafe { core::slice::from_raw_parts(start.add(offset - 2), 2) };
+        assert_eq!(insn, [0x0f, 0x34]); // sysenter
+    }
+}
@@ -0,0 +1,352 @@
+//! Interrupt vector allocation and configuration.
+//!
+//! All 256 IDT gates point to the common entry code, which dispatches the
+//! interrupts in `0x20..=0xff` to the [`IRQ`](crate::trap::IRQ) handlers with
+//! the vector number. Drivers (e.g., of MSI/MSI-X devices) can allocate free
+//! vectors by [`alloc_vector`] or [`alloc_vector_at`], and configure the
+//! privilege level and the interrupt stack of each vector in the IDT.
+//!
+//! The exception vectors (`0x00..0x20`), the legacy syscall vector (`0x80`,
+//! with the `uspace` feature) and the spurious interrupt vector
+//! ([`SPURIOUS_VECTOR`], with the `apic` feature) are reserved.
+//!
+//! [`SPURIOUS_VECTOR`]: crate::apic::SPURIOUS_VECTOR
+
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use x86::irq::{
+    BREAKPOINT_VECTOR, DEBUG_VECTOR, DOUBLE_FAULT_VECTOR, MACHINE_CHECK_VECTOR,
+    NONMASKABLE_INTERRUPT_VECTOR,
+};
+
+use super::gdt::{DEBUG_IST_INDEX, DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+
+/// The first vector that can be allocated.
+pub const FIRST_ALLOC_VECTOR: u8 = 0x20;
+
+/// The number of IST indices used by axcpu (`0..4`), for `#DF`, NMIs, `#MC`
+/// and `#DB`. Indices from it to 6 can be used by [`VectorConfig::ist_index`],
+/// with the stacks set by [`set_ist_stack`], unless the `kpti` feature is
+/// enabled.
+pub const NUM_RESERVED_IST: u16 = 4;
+
+#[cfg(feature = "uspace")]
+const LEGACY_SYSCALL_VECTOR: u8 = 0x80;
+
+/// Configuration of an interrupt vector in the IDT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VectorConfig {
+    /// Whether user space can raise the interrupt with `INT n`, i.e., the
+    /// descriptor privilege level (DPL) of the gate is 3.
+    pub user: bool,
+    /// The index of the interrupt stack table (IST) in the TSS to switch to,
+    /// or [`None`] to use the current stack (or `RSP0` from user space).
+    ///
+    /// It must be [`None`] if the `kpti` feature is enabled, since the stacks
+    /// set by [`set_ist_stack`] are not mapped in the user page table, and the
+    /// interrupt would fault on the first push from user space.
+    pub ist_index: Option<u16>,
+}
+
+impl VectorConfig {
+    const USER_BIT: u8 = 1 << 0;
+    const IST_SHIFT: u8 = 1;
+
+    const fn to_bits(self) -> u8 {
+        let ist = match self.ist_index {
+            Some(index) => index as u8 + 1,
+            None => 0,
+        };
+        (ist << Self::IST_SHIFT) | if self.user { Self::USER_BIT } else { 0 }
+    }
+
+    const fn from_bits(bits: u8) -> Self {
+        let ist = bits >> Self::IST_SHIFT;
+        Self {
+            user: bits & Self::USER_BIT != 0,
+            ist_index: if ist == 0 { None } else { Some(ist as u16 - 1) },
+        }
+    }
+
+    const fn with_ist(index: u16) -> Self {
+        Self {
+            user: false,
+            ist_index: Some(index),
+        }
+    }
+}
+
+const fn default_config(vector: u8) -> VectorConfig {
+    match vector {
+        // switch to a known good stack for exceptions that may occur with a
+        // corrupted kernel stack
+        DOUBLE_FAULT_VECTOR => VectorConfig::with_ist(DOUBLE_FAULT_IST_INDEX),
+        NONMASKABLE_INTERRUPT_VECTOR => VectorConfig::with_ist(NMI_IST_INDEX),
+        MACHINE_CHECK_VECTOR => VectorConfig::with_ist(MACHINE_CHECK_IST_INDEX),
+        DEBUG_VECTOR => VectorConfig::with_ist(DEBUG_IST_INDEX),
+        // enable user space breakpoints
+        BREAKPOINT_VECTOR => VectorConfig {
+            user: true,
+            ist_index: None,
+        },
+        // enable legacy int 0x80 syscall
+        #[cfg(feature = "uspace")]
+        LEGACY_SYSCALL_VECTOR => VectorConfig {
+            user: true,
+            ist_index: None,
+        },
+        _ => VectorConfig {
+            user: false,
+            ist_index: None,
+        },
+    }
+}
+
+const fn is_reserved(vector: u8) -> bool {
+    if vector < FIRST_ALLOC_VECTOR {
+        return true;
+    }
+    #[cfg(feature = "uspace")]
+    if vector == LEGACY_SYSCALL_VECTOR {
+        return true;
+    }
+    #[cfg(feature = "apic")]
+    if vector == super::apic::SPURIOUS_VECTOR {
+        return true;
+    }
+    false
+}
+
+static CONFIGS: [AtomicU8; 256] = {
+    let mut configs = [const { AtomicU8::new(0) }; 256];
+    let mut i = 0;
+    while i < 256 {
+        configs[i] = AtomicU8::new(default_config(i as u8).to_bits());
+        i += 1;
+    }
+    configs
+};
+
+/// Bitmap of the allocated (or reserved) vectors.
+static ALLOCATED: [AtomicU64; 4] = {
+    let mut words = [0; 4];
+    let mut i = 0;
+    while i < 256 {
+        if is_reserved(i as u8) {
+            words[i / 64] |= 1 << (i % 64);
+        }
+        i += 1;
+    }
+    [
+        AtomicU64::new(words[0]),
+        AtomicU64::new(words[1]),
+        AtomicU64::new(words[2]),
+        AtomicU64::new(words[3]),
+    ]
+};
+
+/// Returns the configuration of the given vector.
+pub fn vector_config(vector: u8) -> VectorConfig {
+    VectorConfig::from_bits(CONFIGS[vector as usize].load(Ordering::Acquire))
+}
+
+/// Sets the configuration of the given vector, and updates the IDT if it has
+/// been initialized.
+///
+/// The IDT is shared by all CPUs, so it takes effect on all CPUs.
+///
+/// # Panics
+///
+/// Panics if the vector is reserved, or the IST index is used by axcpu (less
+/// than [`NUM_RESERVED_IST`]) or not less than 7. If the `kpti` feature is
+/// enabled, panics if any IST index is given.
+///
+/// # Safety
+///
+/// The IST stack must have been set on all CPUs that may receive the
+/// interrupt, see [`set_ist_stack`]. The interrupt should not occur while
+/// the configuration is changing.
+pub unsafe fn set_vector_config(vector: u8, config: VectorConfig) {
+    assert!(!is_reserved(vector), "vector {vector:#x} is reserved");
+    if let Some(index) = config.ist_index {
+        if cfg!(feature = "kpti") {
+            panic!("IST stacks are not supported with the `kpti` feature");
+        }
+        assert!(
+            (NUM_RESERVED_IST..7).contains(&index),
+            "invalid IST index: {index}"
+        );
+    }
+    CONFIGS[vector as usize].store(config.to_bits(), Ordering::Release);
+    super::idt::update_entry(vector, config);
+}
+
+fn try_claim(vector: u8) -> bool {
+    let bit = 1 << (vector % 64);
+    ALLOCATED[vector as usize / 64].fetch_or(bit, Ordering::AcqRel) & bit == 0
+}
+
+/// Allocates a free vector in `0x20..=0xff` with the given configuration.
+///
+/// Returns [`None`] if all vectors are in use.
+///
+/// # Safety
+///
+/// See [`set_vector_config`].
+pub unsafe fn alloc_vector(config: VectorConfig) -> Option<u8> {
+    let vector = (FIRST_ALLOC_VECTOR..=u8::MAX).find(|&v| try_claim(v))?;
+    unsafe { set_vector_config(vector, config) };
+    Some(vector)
+}
+
+/// Allocates the given vector with the given configuration, e.g., for a fixed
+/// timer or IPI vector.
+///
+/// Returns `false` if it is reserved or already allocated.
+///
+/// # Safety
+///
+/// See [`set_vector_config`].
+pub unsafe fn alloc_vector_at(vector: u8, config: VectorConfig) -> bool {
+    if is_reserved(vector) || !try_claim(vector) {
+        return false;
+    }
+    unsafe { set_vector_config(vector, config) };
+    true
+}
+
+/// Frees a vector allocated by [`alloc_vector`] or [`alloc_vector_at`], and
+/// resets its configuration.
+///
+/// # Panics
+///
+/// Panics if the vector is reserved or not allocated.
+pub fn free_vector(vector: u8) {
+    assert!(!is_reserved(vector), "vector {vector:#x} is reserved");
+    let bit = 1 << (vector % 64);
+    let word = &ALLOCATED[vector as usize / 64];
+    assert!(
+        word.load(Ordering::Acquire) & bit != 0,
+        "vector {vector:#x} is not allocated"
+    );
+    unsafe { set_vector_config(vector, VectorConfig::default()) };
+    word.fetch_and(!bit, Ordering::AcqRel);
+}
+
+/// Sets the stack of the given interrupt stack table (IST) index in the TSS of
+/// the current CPU.
+///
+/// # Panics
+///
+/// Panics if the index is used by axcpu (less than [`NUM_RESERVED_IST`]), or
+/// not less than 7, or if the `kpti` feature is enabled.
+///
+/// # Safety
+///
+/// The stack must be valid, and no interrupt using the index may occur on the
+/// current CPU while it is changing.
+pub unsafe fn set_ist_stack(index: u16, stack_top: memory_addr::VirtAddr) {
+    if cfg!(feature = "kpti") {
+        panic!("IST stacks are not supported with the `kpti` feature");
+    }
+    assert!(
+        (NUM_RESERVED_IST..7).contains(&index),
+        "invalid IST index: {index}"
+    );
+    unsafe { super::gdt::write_tss_ist(index, stack_top) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_allocated(vector: u8) -> bool {
+        ALLOCATED[vector as usize / 64].load(Ordering::Acquire) & (1 << (vector % 64)) != 0
+    }
+
+    #[test]
+    fn config_bits_round_trip() {
+        for user in [false, true] {
+            for ist_index in [None, Some(0), Some(3), Some(4), Some(6)] {
+                let config = VectorConfig { user, ist_index };
+                assert_eq!(VectorConfig::from_bits(config.to_bits()), config);
+            }
+        }
+        assert_eq!(VectorConfig::default().to_bits(), 0);
+    }
+
+    #[test]
+    fn default_configs() {
+        assert_eq!(
+            default_config(DOUBLE_FAULT_VECTOR).ist_index,
+            Some(DOUBLE_FAULT_IST_INDEX)
+        );
+        assert_eq!(
+            default_config(NONMASKABLE_INTERRUPT_VECTOR).ist_index,
+            Some(NMI_IST_INDEX)
+        );
+        assert!(default_config(BREAKPOINT_VECTOR).user);
+        assert_eq!(default_config(FIRST_ALLOC_VECTOR), VectorConfig::default());
+        #[cfg(feature = "uspace")]
+        assert!(default_config(LEGACY_SYSCALL_VECTOR).user);
+    }
+
+    #[test]
+    fn reserved_vectors() {
+        for vector in 0..=u8::MAX {
+            if is_reserved(vector) {
+                assert!(is_allocated(vector), "{vector:#x}");
+            }
+        }
+        assert!(is_reserved(0));
+        assert!(is_reserved(FIRST_ALLOC_VECTOR - 1));
+        assert!(!is_reserved(FIRST_ALLOC_VECTOR));
+        assert_eq!(is_reserved(0x80), cfg!(feature = "uspace"));
+        #[cfg(feature = "apic")]
+        assert!(is_reserved(super::super::apic::SPURIOUS_VECTOR));
+    }
+
+    #[test]
+    fn alloc_and_free() {
+        let config = VectorConfig {
+            user: true,
+            ist_index: None,
+        };
+        unsafe {
+            assert!(!alloc_vector_at(BREAKPOINT_VECTOR, config));
+            assert!(alloc_vector_at(0xf0, config));
+            assert!(!alloc_vector_at(0xf0, config));
+        }
+        assert_eq!(vector_config(0xf0), config);
+        free_vector(0xf0);
+        assert!(!is_allocated(0xf0));
+        assert_eq!(vector_config(0xf0), VectorConfig::default());
+
+        let vector = unsafe { alloc_vector(config) }.unwrap();
+        assert!(!is_reserved(vector));
+        assert_eq!(vector_config(vector), config);
+        free_vector(vector);
+    }
+
+    #[test]
+    #[should_panic(expected = "is reserved")]
+    fn free_reserved_vector() {
+        free_vector(DOUBLE_FAULT_VECTOR);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not allocated")]
+    fn free_unallocated_vector() {
+        free_vector(0xfe);
+    }
+
+    #[test]
+    #[should_panic]
+    fn reserved_ist_index() {
+        let config = VectorConfig {
+            user: false,
+            ist_index: Some(NMI_IST_INDEX),
+        };
+        unsafe { set_vector_config(0xfd, config) };
+    }
+}